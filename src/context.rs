@@ -1,20 +1,27 @@
 use std::borrow::Cow;
+use std::ops::Range;
 
 use crate::{
-    pipeline::{GpuVertex, Primitive},
+    image::{extend_value, ImageBinding, ImageBrush, ImageData, WgpuImage},
+    pipeline::{
+        DrawCommand, FrameData, GpuGradientStop, GpuVertex, Primitive, PAINT_IMAGE,
+        PAINT_LINEAR_GRADIENT, PAINT_RADIAL_GRADIENT,
+    },
+    stroke,
     svg::Svg,
     text::{WgpuText, WgpuTextLayout},
-    WgpuRenderer,
+    Backend, WgpuRenderer,
 };
-use futures::task::SpawnExt;
 use lyon::lyon_tessellation::{
-    BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeOptions, StrokeTessellator,
-    StrokeVertex, VertexBuffers,
+    BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeTessellator, StrokeVertex,
+    VertexBuffers,
 };
+use lyon::path::{builder::BorderRadii, traits::PathBuilder, Winding};
 use lyon::tessellation;
 use piet::{
-    kurbo::{Affine, Point, Rect, Shape, Size, Vec2},
-    Color, FontFamily, Image, IntoBrush, RenderContext,
+    kurbo::{Affine, Insets, Point, Rect, Shape, Size},
+    Color, FixedGradient, FixedLinearGradient, FixedRadialGradient, FontFamily, GradientStop,
+    Image, ImageFormat, IntoBrush, RenderContext, StrokeStyle, TextLayout,
 };
 
 pub struct WgpuRenderContext<'a> {
@@ -25,8 +32,21 @@ pub struct WgpuRenderContext<'a> {
     inner_text: WgpuText,
     pub(crate) cur_transform: Affine,
     state_stack: Vec<State>,
-    clip_stack: Vec<Rect>,
+    clip_stack: Vec<Clip>,
     pub(crate) primitives: Vec<Primitive>,
+    pub(crate) gradient_stops: Vec<GpuGradientStop>,
+    /// The images the frame paints with.
+    pub(crate) images: Vec<WgpuImage>,
+    pub(crate) commands: Vec<DrawCommand>,
+    /// How many of the indices are covered by `commands`.
+    drawn_indices: u32,
+    /// How many of the commands have been drawn to capture images.
+    drawn_commands: usize,
+}
+
+/// A clip shape, kept to take it out of the stencil buffer again.
+struct Clip {
+    indices: Range<u32>,
 }
 
 #[derive(Default)]
@@ -55,54 +75,487 @@ impl<'a> WgpuRenderContext<'a> {
             state_stack: Vec::new(),
             clip_stack: Vec::new(),
             primitives: Vec::new(),
+            gradient_stops: Vec::new(),
+            images: Vec::new(),
+            commands: Vec::new(),
+            drawn_indices: 0,
+            drawn_commands: 0,
         };
         context.add_primitive();
         context
     }
 
     fn pop_clip(&mut self) {
-        self.clip_stack.pop();
+        self.add_draw_command();
+        let depth = self.clip_stack.len() as u32;
+        if let Some(clip) = self.clip_stack.pop() {
+            self.commands.push(DrawCommand::PopClip {
+                indices: clip.indices,
+                depth,
+            });
+        }
     }
 
-    pub(crate) fn current_clip(&self) -> Option<&Rect> {
-        self.clip_stack.last()
+    /// Add a command drawing the geometry added since the last command under
+    /// the current clips.
+    fn add_draw_command(&mut self) {
+        self.push_draw_command(None);
+    }
+
+    /// Like [`add_draw_command`](Self::add_draw_command), painting the
+    /// geometry's image paints with `image`. Continues the last command if it
+    /// draws the same way and hasn't been drawn yet.
+    fn push_draw_command(&mut self, image: Option<ImageBinding>) {
+        let start = self.drawn_indices;
+        let end = self.geometry.indices.len() as u32;
+        if end <= start {
+            return;
+        }
+        self.drawn_indices = end;
+        let depth = self.clip_stack.len() as u32;
+        if let Some(DrawCommand::Draw {
+            indices,
+            depth: last_depth,
+            image: last_image,
+        }) = self.commands[self.drawn_commands..].last_mut()
+        {
+            if indices.end == start && *last_depth == depth && *last_image == image {
+                indices.end = end;
+                return;
+            }
+        }
+        self.commands.push(DrawCommand::Draw {
+            indices: start..end,
+            depth,
+            image,
+        });
+    }
+
+    /// The commands that are left to draw. The clips of the ones that were
+    /// drawn already come first, to put the stencil buffer back the way they
+    /// left it.
+    fn pending_commands(&self) -> Vec<DrawCommand> {
+        let (drawn, pending) = self.commands.split_at(self.drawn_commands);
+        drawn
+            .iter()
+            .filter(|command| !matches!(command, DrawCommand::Draw { .. }))
+            .chain(pending)
+            .cloned()
+            .collect()
+    }
+
+    /// How the frame's next pass starts: cleared to transparent if it's the
+    /// first, or on top of what captures drew before it.
+    fn load_op(&self) -> wgpu::LoadOp<wgpu::Color> {
+        if self.drawn_commands > 0 {
+            wgpu::LoadOp::Load
+        } else {
+            wgpu::LoadOp::Clear(wgpu::Color::TRANSPARENT)
+        }
+    }
+
+    /// The index of `image` in the frame's images, adding it if it's new.
+    fn add_image(&mut self, image: &WgpuImage) -> usize {
+        match self.images.iter().position(|i| i.id == image.id) {
+            Some(index) => index,
+            None => {
+                self.images.push(image.clone());
+                self.images.len() - 1
+            }
+        }
     }
 
     fn add_primitive(&mut self) {
-        let affine = self.cur_transform.as_coeffs();
-        let translate = [affine[4] as f32, affine[5] as f32];
-        let (clip, clip_rect) = self
-            .current_clip()
-            .map(|r| (1.0, [r.x0 as f32, r.y0 as f32, r.x1 as f32, r.y1 as f32]))
-            .unwrap_or((0.0, [0.0, 0.0, 0.0, 0.0]));
+        self.add_transformed_primitive(self.cur_transform);
+    }
+
+    /// Add a primitive that draws through `transform` instead of the current
+    /// transform.
+    fn add_transformed_primitive(&mut self, transform: Affine) {
+        let affine = transform.as_coeffs();
         self.primitives.push(Primitive {
-            translate,
-            clip,
-            clip_rect,
+            transform_1: [
+                affine[0] as f32,
+                affine[1] as f32,
+                affine[2] as f32,
+                affine[3] as f32,
+            ],
+            transform_2: [affine[4] as f32, affine[5] as f32],
             ..Default::default()
         });
     }
 
+    /// Draw with `brush`. `draw` adds triangles with the vertex color it's
+    /// given, placed relative to `origin`.
+    fn with_brush(&mut self, brush: &Brush, origin: Point, draw: impl FnOnce(&mut Self, [f32; 4])) {
+        if let Brush::Solid(color) = brush {
+            draw(self, format_color(color));
+            return;
+        }
+        let color = self.begin_paint(brush, origin);
+        draw(self, color);
+        self.end_paint(brush);
+    }
+
+    /// Add a primitive that paints with `brush`, for vertices placed relative
+    /// to `origin`. Returns the vertex color to draw with.
+    ///
+    /// The geometry painted with it is finished with
+    /// [`end_paint`](Self::end_paint).
+    fn begin_paint(&mut self, brush: &Brush, origin: Point) -> [f32; 4] {
+        if let Brush::Image(_) = brush {
+            // Image paints are drawn with commands of their own.
+            self.add_draw_command();
+        }
+        self.add_primitive();
+        self.set_paint(brush, origin)
+    }
+
+    /// Make the last primitive paint with `brush`, for vertices placed
+    /// relative to `origin`. Returns the vertex color to draw with.
+    fn set_paint(&mut self, brush: &Brush, origin: Point) -> [f32; 4] {
+        let (paint, [p0, p1], radius, stops, extend) = match brush {
+            Brush::Solid(color) => return format_color(color),
+            Brush::Image(image) => {
+                let size = image.image.size();
+                let to_texture = Affine::scale_non_uniform(
+                    1.0 / size.width.max(1.0),
+                    1.0 / size.height.max(1.0),
+                ) * image.transform.inverse()
+                    * Affine::translate(origin.to_vec2());
+                let affine = to_texture.as_coeffs();
+                let primitive = self.primitives.last_mut().unwrap();
+                primitive.paint = PAINT_IMAGE;
+                primitive.image_transform_1 = [
+                    affine[0] as f32,
+                    affine[1] as f32,
+                    affine[2] as f32,
+                    affine[3] as f32,
+                ];
+                primitive.image_transform_2 = [affine[4] as f32, affine[5] as f32];
+                primitive.image_bounds = image.texture_bounds();
+                return [1.0, 1.0, 1.0, 1.0];
+            }
+            Brush::Linear(gradient, extend) => (
+                PAINT_LINEAR_GRADIENT,
+                [gradient.start, gradient.end],
+                0.0,
+                &gradient.stops,
+                extend,
+            ),
+            // The gradient starts at the focal point and grows into the
+            // circle.
+            Brush::Radial(gradient, extend) => (
+                PAINT_RADIAL_GRADIENT,
+                [gradient.center, gradient.center + gradient.origin_offset],
+                gradient.radius,
+                &gradient.stops,
+                extend,
+            ),
+        };
+        let (p0, p1) = (p0 - origin.to_vec2(), p1 - origin.to_vec2());
+        let extend = extend_value(*extend);
+        let (stops_start, stops_count) = self.add_gradient_stops(stops);
+        let primitive = self.primitives.last_mut().unwrap();
+        primitive.paint = paint;
+        primitive.gradient = [p0.x as f32, p0.y as f32, p1.x as f32, p1.y as f32];
+        primitive.gradient_radius = radius as f32;
+        primitive.stops_start = stops_start;
+        primitive.stops_count = stops_count;
+        primitive.extend = extend;
+        [1.0, 1.0, 1.0, 1.0]
+    }
+
+    /// Finish the geometry painted with `brush` since
+    /// [`begin_paint`](Self::begin_paint), and go back to the current transform's
+    /// primitive.
+    fn end_paint(&mut self, brush: &Brush) {
+        if let Brush::Image(image) = brush {
+            let index = self.add_image(&image.image);
+            self.push_draw_command(Some(image.binding(index)));
+        }
+        self.add_primitive();
+    }
+
+    /// Add the stops of a gradient to the frame, sorted by position, and
+    /// return where they start and how many there are.
+    fn add_gradient_stops(&mut self, stops: &[GradientStop]) -> (u32, u32) {
+        let start = self.gradient_stops.len();
+        let mut stops = stops.to_vec();
+        stops.sort_by(|a, b| {
+            a.pos
+                .partial_cmp(&b.pos)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        if stops.is_empty() {
+            stops.push(GradientStop {
+                pos: 0.0,
+                color: Color::TRANSPARENT,
+            });
+        }
+        self.gradient_stops.extend(stops.iter().map(|stop| {
+            let (r, g, b, a) = stop.color.as_rgba();
+            GpuGradientStop {
+                color: [r as f32, g as f32, b as f32, a as f32],
+                pos: stop.pos,
+                ..Default::default()
+            }
+        }));
+        (start as u32, (self.gradient_stops.len() - start) as u32)
+    }
+
+    /// Make a gradient brush that continues past the ends of `gradient` with
+    /// `extend`. [`RenderContext::gradient`] makes brushes that pad.
+    pub fn gradient_with_extend(
+        &mut self,
+        gradient: impl Into<FixedGradient>,
+        extend: ExtendMode,
+    ) -> Result<Brush, piet::Error> {
+        match gradient.into() {
+            FixedGradient::Linear(linear) => Ok(Brush::Linear(linear, extend)),
+            FixedGradient::Radial(radial) => Ok(Brush::Radial(radial, extend)),
+        }
+    }
+
+    /// Make an image like [`RenderContext::make_image`], with mipmaps so that
+    /// it stays smooth drawn much smaller than its size with
+    /// [`InterpolationMode::Bilinear`](piet::InterpolationMode::Bilinear).
+    pub fn make_image_with_mipmaps(
+        &mut self,
+        width: usize,
+        height: usize,
+        buf: &[u8],
+        format: ImageFormat,
+    ) -> Result<WgpuImage, piet::Error> {
+        self.new_image(width, height, buf, format, true)
+    }
+
+    fn new_image(
+        &self,
+        width: usize,
+        height: usize,
+        buf: &[u8],
+        format: ImageFormat,
+        mipmaps: bool,
+    ) -> Result<WgpuImage, piet::Error> {
+        let max_dimension = self.renderer.max_image_dimension();
+        WgpuImage::from_buf(width, height, buf, format, max_dimension, mipmaps)
+    }
+
+    /// Draw `image` over `dst_rect` as a nine-patch: the corners outside
+    /// `insets` keep their size, the edges between them stretch along
+    /// `dst_rect`'s sides and the center stretches in both directions.
+    ///
+    /// `insets` are in the image's pixels, which are drawn one unit large in
+    /// the corners. When `dst_rect` is too small for the corners, they're
+    /// shrunk to fit. All nine parts are drawn with a single draw command.
+    pub fn draw_image_nine_patch(&mut self, image: &WgpuImage, insets: Insets, dst_rect: Rect) {
+        let size = image.size();
+        let dst_rect = dst_rect.abs();
+        // The edges of the three columns and rows in the image and in
+        // `dst_rect`.
+        let edges = |start: f64, end: f64, inset_start: f64, inset_end: f64, len: f64| {
+            let (inset_start, inset_end) = (inset_start.max(0.0), inset_end.max(0.0));
+            let fit = ((end - start) / (inset_start + inset_end)).min(1.0);
+            (
+                [0.0, inset_start, len - inset_end, len],
+                [start, start + inset_start * fit, end - inset_end * fit, end],
+            )
+        };
+        let (src_x, dst_x) = edges(dst_rect.x0, dst_rect.x1, insets.x0, insets.x1, size.width);
+        let (src_y, dst_y) = edges(dst_rect.y0, dst_rect.y1, insets.y0, insets.y1, size.height);
+
+        let pattern = ImageBrush::new(image).with_extend(ExtendMode::Pad, ExtendMode::Pad);
+        self.add_draw_command();
+        for row in 0..3 {
+            for column in 0..3 {
+                let src = Rect::new(src_x[column], src_y[row], src_x[column + 1], src_y[row + 1]);
+                let dst = Rect::new(dst_x[column], dst_y[row], dst_x[column + 1], dst_y[row + 1]);
+                if src.width() <= 0.0 || src.height() <= 0.0 || dst.area() <= 0.0 {
+                    continue;
+                }
+                let brush = Brush::Image(
+                    pattern
+                        .clone()
+                        .with_transform(stretch(src, dst))
+                        .with_bounds(src),
+                );
+                self.add_primitive();
+                let color = self.set_paint(&brush, Point::ORIGIN);
+                self.tessellate_fill(&dst, color, tessellation::FillRule::NonZero);
+            }
+        }
+        self.end_paint(&Brush::Image(pattern));
+    }
+
+    /// Draw `layout` like [`RenderContext::draw_text`], painting all of its
+    /// glyphs with `brush` instead of their text colors.
+    pub fn draw_text_with_brush(
+        &mut self,
+        layout: &WgpuTextLayout,
+        pos: impl Into<Point>,
+        brush: &impl IntoBrush<Self>,
+    ) {
+        let point: Point = pos.into();
+        let brush = brush
+            .make_brush(self, || Rect::from_origin_size(point, layout.size()))
+            .into_owned();
+        let offset = self.text_offset(point);
+        self.with_brush(&brush, point, |ctx, color| {
+            layout.draw_text(ctx, offset, Some(color))
+        });
+    }
+
+    /// The vertex translation of text drawn at `point`.
+    fn text_offset(&self, point: Point) -> [f32; 2] {
+        // Vertex translations are added after the primitive's transform, so
+        // only its linear part is applied to the position here.
+        let offset = self.cur_transform * point - self.cur_transform * Point::ORIGIN;
+        [offset.x as f32, offset.y as f32]
+    }
+
+    /// Finish the frame by drawing it as a layer on top of `view`.
+    ///
+    /// The draw commands are recorded into `encoder`, which the caller submits
+    /// along with the rest of its frame. This requires a renderer created with
+    /// [`WgpuRenderer::from_device`] or [`WgpuRenderer::new_headless`] that
+    /// renders on the GPU.
+    ///
+    /// The layer is copied pixel for pixel, so `view` must be the size last
+    /// given to [`WgpuRenderer::set_size`]: a larger view is drawn past the
+    /// layer's edges, and a smaller one crops it.
+    pub fn finish_into(
+        &mut self,
+        encoder: &mut wgpu::CommandEncoder,
+        view: &wgpu::TextureView,
+    ) -> Result<(), piet::Error> {
+        self.add_draw_command();
+        let commands = self.pending_commands();
+        let load = self.load_op();
+        let gpu = match &mut self.renderer.backend {
+            Backend::Gpu(gpu) => gpu,
+            Backend::Cpu(_) => return Err(piet::Error::NotSupported),
+        };
+        let layer = gpu.offscreen_view().ok_or(piet::Error::NotSupported)?;
+
+        let frame = FrameData {
+            geometry: &self.geometry,
+            primitives: &self.primitives,
+            gradient_stops: &self.gradient_stops,
+            images: &self.images,
+            commands: &commands,
+        };
+        let upload = gpu.upload(&frame);
+        gpu.submit(upload);
+
+        gpu.pipeline.draw(
+            encoder,
+            &layer,
+            &gpu.msaa,
+            &gpu.stencil,
+            frame.commands,
+            load,
+        );
+        gpu.blit.draw(&gpu.device, encoder, &layer, view);
+
+        Ok(())
+    }
+
+    fn fill_shape(
+        &mut self,
+        shape: impl Shape,
+        brush: &impl IntoBrush<Self>,
+        fill_rule: tessellation::FillRule,
+    ) {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        self.with_brush(&brush, Point::ORIGIN, |ctx, color| {
+            ctx.tessellate_fill(&shape, color, fill_rule)
+        });
+    }
+
+    /// Tessellate the inside of `shape` with `fill_rule`. Rects, circles and
+    /// rounded rects, which are filled the same with either rule, are
+    /// tessellated directly.
+    fn tessellate_fill(
+        &mut self,
+        shape: &impl Shape,
+        color: [f32; 4],
+        fill_rule: tessellation::FillRule,
+    ) {
+        let primitive_id = self.primitives.len() as u32 - 1;
+
+        let options = FillOptions::tolerance(0.02).with_fill_rule(fill_rule);
+        let mut output = BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
+            pos: vertex.position().to_array(),
+            color,
+            primitive_id,
+            ..Default::default()
+        });
+
+        if let Some(rect) = shape.as_rect() {
+            let _ = self.fill_tess.tessellate_rectangle(
+                &lyon::geom::Rect::new(
+                    lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
+                    lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
+                ),
+                &options,
+                &mut output,
+            );
+        } else if let Some(circle) = shape.as_circle() {
+            let _ = self.fill_tess.tessellate_circle(
+                lyon::geom::point(circle.center.x as f32, circle.center.y as f32),
+                circle.radius as f32,
+                &options,
+                &mut output,
+            );
+        } else if let Some(rounded_rect) = shape.as_rounded_rect() {
+            let rect = rounded_rect.rect();
+            let radii = rounded_rect.radii();
+            let mut builder = self.fill_tess.builder(&options, &mut output);
+            builder.add_rounded_rectangle(
+                &lyon::geom::Rect::new(
+                    lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
+                    lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
+                ),
+                &BorderRadii {
+                    top_left: radii.top_left as f32,
+                    top_right: radii.top_right as f32,
+                    bottom_left: radii.bottom_left as f32,
+                    bottom_right: radii.bottom_right as f32,
+                },
+                Winding::Positive,
+            );
+            let _ = builder.build();
+        } else {
+            let path = to_lyon_path(shape);
+            let _ = self.fill_tess.tessellate_path(&path, &options, &mut output);
+        }
+    }
+
     pub fn draw_svg(&mut self, svg: &Svg, rect: Rect, override_color: Option<&Color>) {
         let view_box = svg.tree.svg_node().view_box;
         let view_rect = view_box.rect;
-        let scale =
-            (rect.width() / view_rect.width()).min(rect.height() / view_rect.height()) as f32;
+        let scale = (rect.width() / view_rect.width()).min(rect.height() / view_rect.height());
 
-        let translate = [rect.x0 as f32, rect.y0 as f32];
         let override_color = override_color.map(|c| format_color(c));
         let svg_data = self.renderer.svg_store.get_svg_data(svg);
         let transforms = svg_data.transforms.clone();
         let offset = self.geometry.vertices.len() as u32;
 
         let primitive_id = self.primitives.len() as u32;
-        for transform in transforms {
-            self.add_primitive();
-            let primitive = self.primitives.last_mut().unwrap();
-            primitive.transform_1[0] *= scale * transform[0];
-            primitive.transform_1[3] *= scale * transform[3];
-            primitive.transform_2[0] += scale * transform[4];
-            primitive.transform_2[1] += scale * transform[5];
+        for t in transforms {
+            let transform = self.cur_transform
+                * Affine::translate(rect.origin().to_vec2())
+                * Affine::scale(scale)
+                * Affine::new([
+                    t[0] as f64,
+                    t[1] as f64,
+                    t[2] as f64,
+                    t[3] as f64,
+                    t[4] as f64,
+                    t[5] as f64,
+                ]);
+            self.add_transformed_primitive(transform);
         }
         self.add_primitive();
 
@@ -113,7 +566,6 @@ impl<'a> WgpuRenderContext<'a> {
             .iter()
             .map(|v| {
                 let mut v = v.clone();
-                v.translate = translate;
                 v.primitive_id = primitive_id + v.primitive_id;
                 if let Some(c) = override_color.clone() {
                     v.color = c;
@@ -135,9 +587,23 @@ impl<'a> WgpuRenderContext<'a> {
 #[derive(Clone)]
 pub enum Brush {
     Solid(Color),
+    Linear(FixedLinearGradient, ExtendMode),
+    Radial(FixedRadialGradient, ExtendMode),
+    Image(ImageBrush),
 }
 
-pub struct WgpuImage {}
+/// How a brush continues past the ends of its gradient, or past the edges of
+/// its image along an axis.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtendMode {
+    /// Continue with the colors of the first and last stops, or of the
+    /// image's edge.
+    Pad,
+    /// Repeat the gradient or image.
+    Repeat,
+    /// Repeat the gradient or image, mirroring every other repetition.
+    Reflect,
+}
 
 impl<'a> RenderContext for WgpuRenderContext<'a> {
     type Brush = Brush;
@@ -157,122 +623,13 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         &mut self,
         gradient: impl Into<piet::FixedGradient>,
     ) -> Result<Self::Brush, piet::Error> {
-        todo!()
+        self.gradient_with_extend(gradient, ExtendMode::Pad)
     }
 
     fn clear(&mut self, region: impl Into<Option<Rect>>, color: Color) {}
 
     fn stroke(&mut self, shape: impl Shape, brush: &impl piet::IntoBrush<Self>, width: f64) {
-        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
-        let Brush::Solid(color) = brush;
-        let color = format_color(&color);
-        // let affine = self.cur_transform.as_coeffs();
-        // let translate = [affine[4] as f32, affine[5] as f32];
-        let primitive_id = self.primitives.len() as u32 - 1;
-
-        if let Some(rect) = shape.as_rect() {
-            self.stroke_tess.tessellate_rectangle(
-                &lyon::geom::Rect::new(
-                    lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
-                    lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
-                ),
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
-                    let mut pos = vertex.position_on_path().to_array();
-                    let normal = vertex.normal().to_array();
-                    pos[0] += normal[0] * width as f32 / 2.0;
-                    pos[1] += normal[1] * width as f32 / 2.0;
-                    GpuVertex {
-                        pos,
-                        color,
-                        primitive_id,
-                        ..Default::default()
-                    }
-                }),
-            );
-        } else if let Some(line) = shape.as_line() {
-            let mut builder = lyon::path::Path::builder();
-            builder.begin(lyon::geom::point(line.p0.x as f32, line.p0.y as f32));
-            builder.line_to(lyon::geom::point(line.p1.x as f32, line.p1.y as f32));
-            builder.close();
-            let path = builder.build();
-            self.stroke_tess.tessellate_path(
-                &path,
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
-                    let mut pos = vertex.position_on_path().to_array();
-                    let normal = vertex.normal().to_array();
-                    pos[0] += normal[0] * width as f32 / 2.0;
-                    pos[1] += normal[1] * width as f32 / 2.0;
-                    GpuVertex {
-                        pos,
-                        color,
-                        primitive_id,
-                        ..Default::default()
-                    }
-                }),
-            );
-        } else {
-            let mut builder = lyon::path::Path::builder();
-            let mut in_subpath = false;
-            for el in shape.path_elements(0.01) {
-                match el {
-                    piet::kurbo::PathEl::MoveTo(p) => {
-                        builder.begin(lyon::geom::point(p.x as f32, p.y as f32));
-                        in_subpath = true;
-                    }
-                    piet::kurbo::PathEl::LineTo(p) => {
-                        builder.line_to(lyon::geom::point(p.x as f32, p.y as f32));
-                    }
-                    piet::kurbo::PathEl::QuadTo(ctrl, to) => {
-                        builder.quadratic_bezier_to(
-                            lyon::geom::point(ctrl.x as f32, ctrl.y as f32),
-                            lyon::geom::point(to.x as f32, to.y as f32),
-                        );
-                    }
-                    piet::kurbo::PathEl::CurveTo(c1, c2, p) => {
-                        builder.cubic_bezier_to(
-                            lyon::geom::point(c1.x as f32, c1.y as f32),
-                            lyon::geom::point(c2.x as f32, c2.y as f32),
-                            lyon::geom::point(p.x as f32, p.y as f32),
-                        );
-                    }
-                    piet::kurbo::PathEl::ClosePath => {
-                        in_subpath = false;
-                        builder.close();
-                    }
-                }
-            }
-            if in_subpath {
-                builder.end(false);
-            }
-            let path = builder.build();
-            self.stroke_tess.tessellate_path(
-                &path,
-                &StrokeOptions::tolerance(0.02)
-                    .with_line_width(width as f32)
-                    .with_line_cap(tessellation::LineCap::Round)
-                    .with_line_join(tessellation::LineJoin::Round),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: StrokeVertex| {
-                    let mut pos = vertex.position_on_path().to_array();
-                    let normal = vertex.normal().to_array();
-                    pos[0] += normal[0] * width as f32 / 2.0;
-                    pos[1] += normal[1] * width as f32 / 2.0;
-                    GpuVertex {
-                        pos,
-                        color,
-                        primitive_id,
-                        ..Default::default()
-                    }
-                }),
-            );
-        }
+        self.stroke_styled(shape, brush, width, &StrokeStyle::new());
     }
 
     fn stroke_styled(
@@ -282,28 +639,25 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         width: f64,
         style: &piet::StrokeStyle,
     ) {
-    }
-
-    fn fill(&mut self, shape: impl piet::kurbo::Shape, brush: &impl piet::IntoBrush<Self>) {
-        if let Some(rect) = shape.as_rect() {
-            let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
-            let Brush::Solid(color) = brush;
-            let color = format_color(&color);
-            let primitive_id = self.primitives.len() as u32 - 1;
-            self.fill_tess.tessellate_rectangle(
-                &lyon::geom::Rect::new(
-                    lyon::geom::Point::new(rect.x0 as f32, rect.y0 as f32),
-                    lyon::geom::Size::new(rect.width() as f32, rect.height() as f32),
-                ),
-                &FillOptions::tolerance(0.02).with_fill_rule(tessellation::FillRule::NonZero),
-                &mut BuffersBuilder::new(&mut self.geometry, |vertex: FillVertex| GpuVertex {
+        let brush = brush.make_brush(self, || shape.bounding_box()).into_owned();
+        let (path, options) = stroke::prepare(&shape, width, style);
+        self.with_brush(&brush, Point::ORIGIN, |ctx, color| {
+            let primitive_id = ctx.primitives.len() as u32 - 1;
+            let _ = ctx.stroke_tess.tessellate_path(
+                &to_lyon_path(&path),
+                &options,
+                &mut BuffersBuilder::new(&mut ctx.geometry, |vertex: StrokeVertex| GpuVertex {
                     pos: vertex.position().to_array(),
                     color,
                     primitive_id,
                     ..Default::default()
                 }),
             );
-        }
+        });
+    }
+
+    fn fill(&mut self, shape: impl piet::kurbo::Shape, brush: &impl piet::IntoBrush<Self>) {
+        self.fill_shape(shape, brush, tessellation::FillRule::NonZero);
     }
 
     fn fill_even_odd(
@@ -311,17 +665,23 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         shape: impl piet::kurbo::Shape,
         brush: &impl piet::IntoBrush<Self>,
     ) {
+        self.fill_shape(shape, brush, tessellation::FillRule::EvenOdd);
     }
 
     fn clip(&mut self, shape: impl Shape) {
-        if let Some(rect) = shape.as_rect() {
-            let affine = self.cur_transform.as_coeffs();
-            let rect = rect + Vec2::new(affine[4], affine[5]);
-            self.clip_stack.push(rect);
-            if let Some(state) = self.state_stack.last_mut() {
-                state.n_clip += 1;
-            }
-            self.add_primitive();
+        self.add_draw_command();
+        let start = self.geometry.indices.len() as u32;
+        self.tessellate_fill(&shape, [0.0; 4], tessellation::FillRule::NonZero);
+        let indices = start..self.geometry.indices.len() as u32;
+        self.drawn_indices = indices.end;
+
+        self.commands.push(DrawCommand::PushClip {
+            indices: indices.clone(),
+            depth: self.clip_stack.len() as u32 + 1,
+        });
+        self.clip_stack.push(Clip { indices });
+        if let Some(state) = self.state_stack.last_mut() {
+            state.n_clip += 1;
         }
     }
 
@@ -330,9 +690,8 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     }
 
     fn draw_text(&mut self, layout: &Self::TextLayout, pos: impl Into<piet::kurbo::Point>) {
-        let point: Point = pos.into();
-        let translate = [point.x as f32, point.y as f32];
-        layout.draw_text(self, translate);
+        let offset = self.text_offset(pos.into());
+        layout.draw_text(self, offset, None);
     }
 
     fn save(&mut self) -> Result<(), piet::Error> {
@@ -358,46 +717,19 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
     }
 
     fn finish(&mut self) -> Result<(), piet::Error> {
-        self.renderer.ensure_encoder();
-        let mut encoder = self.renderer.take_encoder();
-
-        self.renderer.pipeline.upload_data(
-            &self.renderer.device,
-            &mut self.renderer.staging_belt.borrow_mut(),
-            &mut encoder,
-            &self.geometry,
-            &self.primitives,
-        );
-
-        let texture = self
-            .renderer
-            .surface
-            .get_current_texture()
-            .map_err(|e| piet::Error::NotSupported)?;
-        let view = texture
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());
-
-        self.renderer.pipeline.draw(
-            &self.renderer.device,
-            &mut encoder,
-            &view,
-            &self.renderer.msaa,
-            &self.geometry,
-        );
-
-        self.renderer.staging_belt.borrow_mut().finish();
-        self.renderer.queue.submit(Some(encoder.finish()));
-        texture.present();
-
-        self.renderer
-            .local_pool
-            .spawner()
-            .spawn(self.renderer.staging_belt.borrow_mut().recall())
-            .expect("Recall staging belt");
-        self.renderer.local_pool.run_until_stalled();
-
-        Ok(())
+        self.add_draw_command();
+        let commands = self.pending_commands();
+        let load = self.load_op();
+        self.renderer.draw(
+            &FrameData {
+                geometry: &self.geometry,
+                primitives: &self.primitives,
+                gradient_stops: &self.gradient_stops,
+                images: &self.images,
+                commands: &commands,
+            },
+            load,
+        )
     }
 
     fn transform(&mut self, transform: Affine) {
@@ -415,7 +747,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         buf: &[u8],
         format: piet::ImageFormat,
     ) -> Result<Self::Image, piet::Error> {
-        todo!()
+        self.new_image(width, height, buf, format, false)
     }
 
     fn draw_image(
@@ -424,7 +756,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         dst_rect: impl Into<piet::kurbo::Rect>,
         interp: piet::InterpolationMode,
     ) {
-        todo!()
+        self.draw_image_area(image, image.size().to_rect(), dst_rect, interp);
     }
 
     fn draw_image_area(
@@ -434,14 +766,70 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         dst_rect: impl Into<piet::kurbo::Rect>,
         interp: piet::InterpolationMode,
     ) {
-        todo!()
+        let src_rect = src_rect.into();
+        let dst_rect = dst_rect.into();
+        if src_rect.width() == 0.0 || src_rect.height() == 0.0 {
+            return;
+        }
+        let brush = ImageBrush::new(image)
+            .with_transform(stretch(src_rect, dst_rect))
+            .with_bounds(src_rect)
+            .with_extend(ExtendMode::Pad, ExtendMode::Pad)
+            .with_interpolation(interp);
+        self.fill(dst_rect, &brush);
     }
 
     fn capture_image_area(
         &mut self,
         src_rect: impl Into<piet::kurbo::Rect>,
     ) -> Result<Self::Image, piet::Error> {
-        todo!()
+        // `src_rect` is in the current transform's coordinates. Transforms
+        // that rotate or skew it capture its bounding box.
+        let rect = (self.current_transform() * src_rect.into().to_path(0.1))
+            .bounding_box()
+            .scale_from_origin(self.renderer.scale())
+            .expand();
+        let (width, height) = (rect.width() as usize, rect.height() as usize);
+        if width == 0 || height == 0 {
+            return Err(piet::Error::InvalidInput);
+        }
+
+        // What's outside the frame is transparent.
+        let mut pixels = vec![0; width * height * 4];
+        let visible = rect.intersect(self.renderer.size.to_rect());
+        if visible.area() > 0.0 {
+            self.add_draw_command();
+            let commands = self.pending_commands();
+            let load = self.load_op();
+            let visible_pixels = self.renderer.capture(
+                &FrameData {
+                    geometry: &self.geometry,
+                    primitives: &self.primitives,
+                    gradient_stops: &self.gradient_stops,
+                    images: &self.images,
+                    commands: &commands,
+                },
+                [
+                    visible.x0 as u32,
+                    visible.y0 as u32,
+                    visible.width() as u32,
+                    visible.height() as u32,
+                ],
+                load,
+            )?;
+            self.drawn_commands = self.commands.len();
+
+            let row_bytes = visible.width() as usize * 4;
+            let offset = (visible.origin() - rect.origin()).to_point();
+            for (i, row) in visible_pixels.chunks_exact(row_bytes).enumerate() {
+                let start = ((offset.y as usize + i) * width + offset.x as usize) * 4;
+                pixels[start..start + row_bytes].copy_from_slice(row);
+            }
+        }
+
+        // The frame's pixels are premultiplied linear colors, sRGB encoded,
+        // just like the texels of images.
+        Ok(WgpuImage::from_data(ImageData::new(width, height, pixels)))
     }
 
     fn blurred_rect(
@@ -453,10 +841,8 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
         let rect = rect.inflate(3.0 * blur_radius, 3.0 * blur_radius);
         let blur_rect = rect.inflate(-3.0 * blur_radius, -3.0 * blur_radius);
         let brush = brush.make_brush(self, || rect).into_owned();
-        let Brush::Solid(color) = brush;
-        let color = format_color(&color);
 
-        self.add_primitive();
+        let color = self.begin_paint(&brush, Point::ORIGIN);
         let primitive = self.primitives.last_mut().unwrap();
         primitive.blur_radius = blur_radius as f32;
         primitive.blur_rect = [
@@ -480,7 +866,7 @@ impl<'a> RenderContext for WgpuRenderContext<'a> {
                 ..Default::default()
             }),
         );
-        self.add_primitive();
+        self.end_paint(&brush);
     }
 
     fn current_transform(&self) -> piet::kurbo::Affine {
@@ -507,10 +893,62 @@ impl<'a> IntoBrush<WgpuRenderContext<'a>> for Brush {
     }
 }
 
-impl Image for WgpuImage {
-    fn size(&self) -> piet::kurbo::Size {
-        todo!()
+impl<'a> IntoBrush<WgpuRenderContext<'a>> for ImageBrush {
+    fn make_brush<'b>(
+        &'b self,
+        _piet: &mut WgpuRenderContext,
+        _bbox: impl FnOnce() -> piet::kurbo::Rect,
+    ) -> std::borrow::Cow<'b, Brush> {
+        Cow::Owned(Brush::Image(self.clone()))
+    }
+}
+
+/// The transform that stretches `src` over `dst`.
+fn stretch(src: Rect, dst: Rect) -> Affine {
+    Affine::translate(dst.origin().to_vec2())
+        * Affine::scale_non_uniform(dst.width() / src.width(), dst.height() / src.height())
+        * Affine::translate(-src.origin().to_vec2())
+}
+
+/// Convert a kurbo shape into a lyon path, keeping its curves.
+fn to_lyon_path(shape: &impl Shape) -> lyon::path::Path {
+    let mut builder = lyon::path::Path::builder();
+    let mut in_subpath = false;
+    for el in shape.path_elements(0.01) {
+        match el {
+            piet::kurbo::PathEl::MoveTo(p) => {
+                if in_subpath {
+                    builder.end(false);
+                }
+                builder.begin(lyon::geom::point(p.x as f32, p.y as f32));
+                in_subpath = true;
+            }
+            piet::kurbo::PathEl::LineTo(p) => {
+                builder.line_to(lyon::geom::point(p.x as f32, p.y as f32));
+            }
+            piet::kurbo::PathEl::QuadTo(ctrl, to) => {
+                builder.quadratic_bezier_to(
+                    lyon::geom::point(ctrl.x as f32, ctrl.y as f32),
+                    lyon::geom::point(to.x as f32, to.y as f32),
+                );
+            }
+            piet::kurbo::PathEl::CurveTo(c1, c2, p) => {
+                builder.cubic_bezier_to(
+                    lyon::geom::point(c1.x as f32, c1.y as f32),
+                    lyon::geom::point(c2.x as f32, c2.y as f32),
+                    lyon::geom::point(p.x as f32, p.y as f32),
+                );
+            }
+            piet::kurbo::PathEl::ClosePath => {
+                in_subpath = false;
+                builder.close();
+            }
+        }
+    }
+    if in_subpath {
+        builder.end(false);
     }
+    builder.build()
 }
 
 pub fn from_linear(x: f32) -> f32 {
@@ -0,0 +1,806 @@
+//! A CPU rasterizer for the geometry the GPU pipeline draws.
+//!
+//! It takes the same frames as
+//! [`Pipeline::upload_data`](crate::pipeline::Pipeline::upload_data) and
+//! [`Pipeline::draw`](crate::pipeline::Pipeline::draw), and follows
+//! `shader/geometry.wgsl` step by step, so it doubles as an executable spec
+//! for the shader. The frame is 4x multisampled with the standard sample
+//! pattern and blended in linear space into sRGB samples, like the
+//! `Rgba8UnormSrgb` frame of a headless GPU renderer. Every sample also has a
+//! stencil value for clipping.
+
+use piet::kurbo::Size;
+
+use crate::image::{ImageBinding, ImageData};
+use crate::pipeline::{
+    Cache, DrawCommand, FrameData, GpuGradientStop, GpuVertex, Primitive, EXTEND_REFLECT,
+    EXTEND_REPEAT, PAINT_IMAGE, PAINT_LINEAR_GRADIENT, PAINT_RADIAL_GRADIENT,
+};
+
+const SAMPLE_COUNT: usize = 4;
+
+/// Positions of the samples inside a pixel, the standard 4x pattern.
+const SAMPLE_POSITIONS: [[f64; 2]; SAMPLE_COUNT] = [
+    [0.375, 0.125],
+    [0.875, 0.375],
+    [0.125, 0.625],
+    [0.625, 0.875],
+];
+
+pub(crate) struct Rasterizer {
+    width: usize,
+    height: usize,
+    pub(crate) scale: f64,
+    /// The multisampled frame, `SAMPLE_COUNT` sRGB encoded samples per pixel.
+    samples: Vec<[u8; 4]>,
+    /// The stencil value of every sample, cleared for every frame.
+    stencil: Vec<u8>,
+    /// Maps an sRGB encoded channel to linear.
+    decode: Vec<f32>,
+}
+
+/// The output of the vertex stage that's interpolated across a triangle.
+#[derive(Clone, Copy)]
+struct Varyings {
+    color: [f32; 4],
+    pos: [f32; 2],
+    tex: f32,
+    tex_pos: [f32; 2],
+}
+
+/// What the fragments of a frame read besides their varyings.
+struct Resources<'a> {
+    primitives: &'a [Primitive],
+    gradient_stops: &'a [GpuGradientStop],
+    atlas: &'a Cache,
+    /// The image bound to the triangles being drawn.
+    image: Option<(&'a ImageData, &'a ImageBinding)>,
+}
+
+/// The stencil test and operation a triangle is drawn with, like the
+/// pipelines of [`Pipeline`](crate::pipeline::Pipeline). Samples pass the test
+/// if their stencil value equals the reference.
+#[derive(Clone, Copy)]
+enum Stencil {
+    /// Blend the fragments into the samples.
+    Keep(u32),
+    /// Only increment the stencil values.
+    Increment(u32),
+    /// Only decrement the stencil values.
+    Decrement(u32),
+}
+
+impl Stencil {
+    fn reference(self) -> u32 {
+        match self {
+            Stencil::Keep(reference)
+            | Stencil::Increment(reference)
+            | Stencil::Decrement(reference) => reference,
+        }
+    }
+}
+
+impl Rasterizer {
+    pub(crate) fn new() -> Self {
+        let decode = (0..=255)
+            .map(|i| srgb_to_linear(i as f32 / 255.0))
+            .collect();
+        Self {
+            width: 0,
+            height: 0,
+            scale: 1.0,
+            samples: Vec::new(),
+            stencil: Vec::new(),
+            decode,
+        }
+    }
+
+    pub(crate) fn set_size(&mut self, size: Size) {
+        self.width = size.width as usize;
+        self.height = size.height as usize;
+        self.samples = vec![[0; 4]; self.width * self.height * SAMPLE_COUNT];
+        self.stencil = vec![0; self.width * self.height * SAMPLE_COUNT];
+    }
+
+    /// Draw a frame on top of what's already there.
+    /// Clear the frame to transparent.
+    pub(crate) fn clear(&mut self) {
+        self.samples.iter_mut().for_each(|s| *s = [0; 4]);
+    }
+
+    pub(crate) fn draw(&mut self, frame: &FrameData, atlas: &Cache) {
+        let FrameData {
+            geometry,
+            primitives,
+            gradient_stops,
+            images,
+            commands,
+        } = *frame;
+        if primitives.is_empty() {
+            return;
+        }
+        let mut resources = Resources {
+            primitives,
+            gradient_stops,
+            atlas,
+            image: None,
+        };
+        self.stencil.iter_mut().for_each(|s| *s = 0);
+        for command in commands {
+            resources.image = match command {
+                DrawCommand::Draw {
+                    image: Some(binding),
+                    ..
+                } => Some((&*images[binding.image].data, binding)),
+                _ => None,
+            };
+            let (indices, stencil) = match command {
+                DrawCommand::Draw { indices, depth, .. } => (indices, Stencil::Keep(*depth)),
+                DrawCommand::PushClip { indices, depth } => {
+                    (indices, Stencil::Increment(depth - 1))
+                }
+                DrawCommand::PopClip { indices, depth } => (indices, Stencil::Decrement(*depth)),
+            };
+            let indices = &geometry.indices[indices.start as usize..indices.end as usize];
+            for triangle in indices.chunks_exact(3) {
+                let vertices = [
+                    &geometry.vertices[triangle[0] as usize],
+                    &geometry.vertices[triangle[1] as usize],
+                    &geometry.vertices[triangle[2] as usize],
+                ];
+                self.draw_triangle(vertices, &resources, stencil);
+            }
+        }
+    }
+
+    /// Resolve the frame into 8-bit RGBA rows with premultiplied alpha.
+    pub(crate) fn read_pixels(&self) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(self.width * self.height * 4);
+        for samples in self.samples.chunks_exact(SAMPLE_COUNT) {
+            let mut sum = [0.0; 4];
+            for sample in samples {
+                for c in 0..3 {
+                    sum[c] += self.decode[sample[c] as usize];
+                }
+                sum[3] += sample[3] as f32 / 255.0;
+            }
+            let n = SAMPLE_COUNT as f32;
+            pixels.extend_from_slice(&[
+                to_unorm(linear_to_srgb(sum[0] / n)),
+                to_unorm(linear_to_srgb(sum[1] / n)),
+                to_unorm(linear_to_srgb(sum[2] / n)),
+                to_unorm(sum[3] / n),
+            ]);
+        }
+        pixels
+    }
+
+    fn draw_triangle(
+        &mut self,
+        vertices: [&GpuVertex; 3],
+        resources: &Resources,
+        stencil: Stencil,
+    ) {
+        let primitives = resources.primitives;
+        // Everything but the varyings comes from the first vertex, the
+        // vertices of a triangle always share a primitive.
+        let first = primitive(primitives, vertices[0].primitive_id);
+
+        let mut points = [[0.0; 2]; 3];
+        let mut varyings = [Varyings {
+            color: [0.0; 4],
+            pos: [0.0; 2],
+            tex: 0.0,
+            tex_pos: [0.0; 2],
+        }; 3];
+        for i in 0..3 {
+            let (point, v) =
+                self.vertex(vertices[i], primitive(primitives, vertices[i].primitive_id));
+            points[i] = point;
+            varyings[i] = v;
+        }
+
+        let area = edge(points[0], points[1], points[2]);
+        if area == 0.0 || !area.is_finite() {
+            return;
+        }
+        // Wind the triangle so that its inside is on the positive side of
+        // every edge.
+        if area < 0.0 {
+            points.swap(1, 2);
+            varyings.swap(1, 2);
+        }
+        let area = area.abs();
+        let edges = [(1, 2), (2, 0), (0, 1)];
+
+        // How `pos` changes from pixel to pixel, which is the same across
+        // the triangle.
+        let [p0, p1, p2] = points;
+        let derivative = |f: &dyn Fn(&Varyings) -> f32| {
+            let (d1, d2) = (
+                (f(&varyings[1]) - f(&varyings[0])) as f64,
+                (f(&varyings[2]) - f(&varyings[0])) as f64,
+            );
+            [
+                ((d1 * (p2[1] - p0[1]) - d2 * (p1[1] - p0[1])) / area) as f32,
+                ((d2 * (p1[0] - p0[0]) - d1 * (p2[0] - p0[0])) / area) as f32,
+            ]
+        };
+        let (dx, dy) = (derivative(&|v| v.pos[0]), derivative(&|v| v.pos[1]));
+        let derivatives = [[dx[0], dy[0]], [dx[1], dy[1]]];
+
+        let min_x = points.iter().map(|p| p[0]).fold(f64::INFINITY, f64::min);
+        let max_x = points
+            .iter()
+            .map(|p| p[0])
+            .fold(f64::NEG_INFINITY, f64::max);
+        let min_y = points.iter().map(|p| p[1]).fold(f64::INFINITY, f64::min);
+        let max_y = points
+            .iter()
+            .map(|p| p[1])
+            .fold(f64::NEG_INFINITY, f64::max);
+        let x0 = min_x.floor().max(0.0) as usize;
+        let y0 = min_y.floor().max(0.0) as usize;
+        let x1 = (max_x.ceil().max(0.0) as usize).min(self.width);
+        let y1 = (max_y.ceil().max(0.0) as usize).min(self.height);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let pixel = (y * self.width + x) * SAMPLE_COUNT;
+                let mut covered = [false; SAMPLE_COUNT];
+                for (sample, offset) in SAMPLE_POSITIONS.iter().enumerate() {
+                    let p = [x as f64 + offset[0], y as f64 + offset[1]];
+                    covered[sample] = edges
+                        .iter()
+                        .all(|&(a, b)| inside(points[a], points[b], edge(points[a], points[b], p)))
+                        && self.stencil[pixel + sample] as u32 == stencil.reference();
+                }
+                if !covered.contains(&true) {
+                    continue;
+                }
+
+                // The fragment is shaded once per pixel, at its center.
+                let center = [x as f64 + 0.5, y as f64 + 0.5];
+                let mut weights = [0.0; 3];
+                for (weight, &(a, b)) in weights.iter_mut().zip(edges.iter()) {
+                    *weight = (edge(points[a], points[b], center) / area) as f32;
+                }
+                let v = interpolate(&varyings, weights);
+                let color = match self.fragment(&v, derivatives, first, resources) {
+                    Some(color) => color,
+                    None => continue,
+                };
+
+                for (sample, _) in covered.iter().enumerate().filter(|(_, c)| **c) {
+                    let value = &mut self.stencil[pixel + sample];
+                    match stencil {
+                        Stencil::Keep(_) => self.blend(pixel + sample, color),
+                        Stencil::Increment(_) => *value = value.saturating_add(1),
+                        Stencil::Decrement(_) => *value = value.saturating_sub(1),
+                    }
+                }
+            }
+        }
+    }
+
+    /// `vs_main`, returning the position in physical pixels.
+    fn vertex(&self, input: &GpuVertex, primitive: &Primitive) -> ([f64; 2], Varyings) {
+        let t1 = primitive.transform_1;
+        let t2 = primitive.transform_2;
+        let transformed = [
+            t1[0] * input.pos[0] + t1[2] * input.pos[1] + t2[0],
+            t1[1] * input.pos[0] + t1[3] * input.pos[1] + t2[1],
+        ];
+        let scale = self.scale as f32;
+        let position = [
+            (transformed[0] * primitive.scale[0] + primitive.translate[0] + input.translate[0])
+                * scale,
+            (transformed[1] * primitive.scale[1] + primitive.translate[1] + input.translate[1])
+                * scale,
+        ];
+        (
+            [position[0] as f64, position[1] as f64],
+            Varyings {
+                color: input.color,
+                pos: input.pos,
+                tex: input.tex,
+                tex_pos: input.tex_pos,
+            },
+        )
+    }
+
+    /// `fs_main`, returning `None` where the shader discards. `derivatives`
+    /// are how `pos` changes from one pixel to the next along x and along y.
+    fn fragment(
+        &self,
+        input: &Varyings,
+        derivatives: [[f32; 2]; 2],
+        primitive: &Primitive,
+        resources: &Resources,
+    ) -> Option<[f32; 4]> {
+        let mut color = input.color;
+        let paint = match primitive.paint {
+            PAINT_LINEAR_GRADIENT => Some(gradient_color(
+                primitive,
+                resources.gradient_stops,
+                linear_gradient_t(primitive, input.pos),
+            )),
+            PAINT_RADIAL_GRADIENT => match radial_gradient_t(primitive, input.pos) {
+                t if t < 0.0 => Some([0.0; 4]),
+                t => Some(gradient_color(primitive, resources.gradient_stops, t)),
+            },
+            PAINT_IMAGE => {
+                Some(self.image_color(primitive, resources.image, input.pos, derivatives))
+            }
+            _ => None,
+        };
+        if let Some(paint) = paint {
+            for c in 0..4 {
+                color[c] *= paint[c];
+            }
+        }
+
+        if primitive.blur_radius > 0.0 {
+            let rect = primitive.blur_rect;
+            let pos = input.pos;
+            if rect[0] <= pos[0] && pos[0] <= rect[2] && rect[1] <= pos[1] && pos[1] <= rect[3] {
+                color[3] = 0.0;
+            } else {
+                color[3] *= box_shadow(
+                    [rect[0], rect[1]],
+                    [rect[2], rect[3]],
+                    pos,
+                    primitive.blur_radius,
+                );
+            }
+        }
+
+        if input.tex > 0.0 {
+            let alpha = sample_atlas(resources.atlas, input.tex_pos);
+            if alpha <= 0.0 {
+                return None;
+            }
+            color[3] *= alpha;
+        }
+
+        Some(color)
+    }
+
+    /// The color of an image paint at `pos`, in linear space. Texels are
+    /// filtered premultiplied, like the texture of an
+    /// [`IMAGE_FORMAT`](crate::image::IMAGE_FORMAT) image. Without an image
+    /// it's transparent, like the empty image bind group.
+    ///
+    /// Bilinear sampling picks mip levels from how far apart the texels of
+    /// neighboring pixels are, given by the `derivatives` of `pos`.
+    fn image_color(
+        &self,
+        primitive: &Primitive,
+        image: Option<(&ImageData, &ImageBinding)>,
+        pos: [f32; 2],
+        derivatives: [[f32; 2]; 2],
+    ) -> [f32; 4] {
+        let (data, binding) = match image {
+            Some((data, binding)) if data.width > 0 && data.height > 0 => (data, binding),
+            _ => return [0.0; 4],
+        };
+        let t1 = primitive.image_transform_1;
+        let t2 = primitive.image_transform_2;
+        let uv = [
+            t1[0] * pos[0] + t1[2] * pos[1] + t2[0],
+            t1[1] * pos[0] + t1[3] * pos[1] + t2[1],
+        ];
+        let level = match binding.filter {
+            wgpu::FilterMode::Nearest => 0.0,
+            wgpu::FilterMode::Linear => {
+                let texels = |d: [f32; 2]| {
+                    let du = (t1[0] * d[0] + t1[2] * d[1]) * data.width as f32;
+                    let dv = (t1[1] * d[0] + t1[3] * d[1]) * data.height as f32;
+                    (du * du + dv * dv).sqrt()
+                };
+                let lod = texels(derivatives[0]).max(texels(derivatives[1])).log2();
+                lod.clamp(0.0, (data.mip_level_count - 1) as f32)
+            }
+        };
+        let [x0, y0, x1, y1] = primitive.image_bounds;
+        let uv = [uv[0].clamp(x0, x1), uv[1].clamp(y0, y1)];
+        let base = level.floor();
+        let mut color = self.sample_level(data, binding, base as u32, uv);
+        if level > base {
+            let next = self.sample_level(data, binding, base as u32 + 1, uv);
+            for c in 0..4 {
+                color[c] += (next[c] - color[c]) * (level - base);
+            }
+        }
+        unpremultiply(color)
+    }
+
+    /// Sample mip level `level` of an image at `uv`, premultiplied.
+    fn sample_level(
+        &self,
+        data: &ImageData,
+        binding: &ImageBinding,
+        level: u32,
+        uv: [f32; 2],
+    ) -> [f32; 4] {
+        let (width, height, pixels) = data.mip_level(level);
+        let texel = |x: f32, y: f32| {
+            let x = address(x as i64, width, binding.extend[0]);
+            let y = address(y as i64, height, binding.extend[1]);
+            let i = (y * width + x) * 4;
+            let p = &pixels[i..i + 4];
+            [
+                self.decode[p[0] as usize],
+                self.decode[p[1] as usize],
+                self.decode[p[2] as usize],
+                p[3] as f32 / 255.0,
+            ]
+        };
+        let x = uv[0] * width as f32;
+        let y = uv[1] * height as f32;
+        match binding.filter {
+            wgpu::FilterMode::Nearest => texel(x.floor(), y.floor()),
+            wgpu::FilterMode::Linear => {
+                let (x, y) = (x - 0.5, y - 0.5);
+                let (fx, fy) = (x - x.floor(), y - y.floor());
+                let (x, y) = (x.floor(), y.floor());
+                let (a, b) = (texel(x, y), texel(x + 1.0, y));
+                let (c, d) = (texel(x, y + 1.0), texel(x + 1.0, y + 1.0));
+                let mut color = [0.0; 4];
+                for i in 0..4 {
+                    let top = a[i] * (1.0 - fx) + b[i] * fx;
+                    let bottom = c[i] * (1.0 - fx) + d[i] * fx;
+                    color[i] = top * (1.0 - fy) + bottom * fy;
+                }
+                color
+            }
+        }
+    }
+
+    /// `BlendState::ALPHA_BLENDING` into an sRGB sample.
+    fn blend(&mut self, index: usize, color: [f32; 4]) {
+        let dst = self.samples[index];
+        let src_alpha = color[3].clamp(0.0, 1.0);
+        let mut out = [0; 4];
+        for c in 0..3 {
+            let src = color[c].clamp(0.0, 1.0);
+            let dst = self.decode[dst[c] as usize];
+            out[c] = to_unorm(linear_to_srgb(src * src_alpha + dst * (1.0 - src_alpha)));
+        }
+        out[3] = to_unorm(src_alpha + dst[3] as f32 / 255.0 * (1.0 - src_alpha));
+        self.samples[index] = out;
+    }
+}
+
+/// Out of range ids read the last uploaded primitive, like a bounds checked
+/// storage buffer read.
+fn primitive(primitives: &[Primitive], id: u32) -> &Primitive {
+    &primitives[(id as usize).min(primitives.len() - 1)]
+}
+
+/// Twice the signed area of the triangle `a`, `b`, `p`.
+fn edge(a: [f64; 2], b: [f64; 2], p: [f64; 2]) -> f64 {
+    (b[0] - a[0]) * (p[1] - a[1]) - (b[1] - a[1]) * (p[0] - a[0])
+}
+
+/// Whether a sample with edge function `e` for edge `a`-`b` is inside.
+///
+/// Samples exactly on an edge follow the top-left rule, so triangles that
+/// share an edge don't both cover it.
+fn inside(a: [f64; 2], b: [f64; 2], e: f64) -> bool {
+    if e != 0.0 {
+        return e > 0.0;
+    }
+    let dx = b[0] - a[0];
+    let dy = b[1] - a[1];
+    dy < 0.0 || (dy == 0.0 && dx > 0.0)
+}
+
+fn interpolate(varyings: &[Varyings; 3], weights: [f32; 3]) -> Varyings {
+    let mix = |f: &dyn Fn(&Varyings) -> f32| {
+        f(&varyings[0]) * weights[0] + f(&varyings[1]) * weights[1] + f(&varyings[2]) * weights[2]
+    };
+    Varyings {
+        color: [
+            mix(&|v| v.color[0]),
+            mix(&|v| v.color[1]),
+            mix(&|v| v.color[2]),
+            mix(&|v| v.color[3]),
+        ],
+        pos: [mix(&|v| v.pos[0]), mix(&|v| v.pos[1])],
+        tex: mix(&|v| v.tex),
+        tex_pos: [mix(&|v| v.tex_pos[0]), mix(&|v| v.tex_pos[1])],
+    }
+}
+
+/// A linear, clamp to edge sample of the glyph atlas.
+fn sample_atlas(atlas: &Cache, tex_pos: [f32; 2]) -> f32 {
+    let width = atlas.width as usize;
+    let height = atlas.height as usize;
+    if atlas.pixels.len() < width * height || width == 0 || height == 0 {
+        return 0.0;
+    }
+    let x = tex_pos[0] * width as f32 - 0.5;
+    let y = tex_pos[1] * height as f32 - 0.5;
+    let (fx, fy) = (x - x.floor(), y - y.floor());
+    let texel = |x: f32, y: f32| {
+        let x = (x.max(0.0) as usize).min(width - 1);
+        let y = (y.max(0.0) as usize).min(height - 1);
+        atlas.pixels[y * width + x] as f32 / 255.0
+    };
+    let (x, y) = (x.floor(), y.floor());
+    let top = texel(x, y) * (1.0 - fx) + texel(x + 1.0, y) * fx;
+    let bottom = texel(x, y + 1.0) * (1.0 - fx) + texel(x + 1.0, y + 1.0) * fx;
+    top * (1.0 - fy) + bottom * fy
+}
+
+/// The texel a sampler with the address mode of `extend` reads for texel
+/// coordinate `i` of an axis with `n` texels.
+fn address(i: i64, n: usize, extend: u32) -> usize {
+    let n = n as i64;
+    let i = match extend {
+        EXTEND_REPEAT => i.rem_euclid(n),
+        EXTEND_REFLECT => {
+            let i = i.rem_euclid(2 * n);
+            if i < n {
+                i
+            } else {
+                2 * n - 1 - i
+            }
+        }
+        _ => i.clamp(0, n - 1),
+    };
+    i as usize
+}
+
+fn unpremultiply(color: [f32; 4]) -> [f32; 4] {
+    if color[3] <= 0.0 {
+        return [0.0; 4];
+    }
+    [
+        color[0] / color[3],
+        color[1] / color[3],
+        color[2] / color[3],
+        color[3],
+    ]
+}
+
+fn linear_gradient_t(primitive: &Primitive, pos: [f32; 2]) -> f32 {
+    let [x0, y0, x1, y1] = primitive.gradient;
+    let direction = [x1 - x0, y1 - y0];
+    let length_squared = direction[0] * direction[0] + direction[1] * direction[1];
+    if length_squared <= 0.0 {
+        return 0.0;
+    }
+    ((pos[0] - x0) * direction[0] + (pos[1] - y0) * direction[1]) / length_squared
+}
+
+/// Where `pos` is on a radial gradient: the largest `t` for which it's on the
+/// circle around `mix(focal, center, t)` with radius `t * radius`. Negative
+/// where there's no such circle.
+fn radial_gradient_t(primitive: &Primitive, pos: [f32; 2]) -> f32 {
+    let [cx, cy, fx, fy] = primitive.gradient;
+    let radius = primitive.gradient_radius;
+    let cd = [cx - fx, cy - fy];
+    let pd = [pos[0] - fx, pos[1] - fy];
+    let a = cd[0] * cd[0] + cd[1] * cd[1] - radius * radius;
+    let b = pd[0] * cd[0] + pd[1] * cd[1];
+    let c = pd[0] * pd[0] + pd[1] * pd[1];
+    if a == 0.0 {
+        if b <= 0.0 {
+            return -1.0;
+        }
+        return c / (2.0 * b);
+    }
+    let discriminant = b * b - a * c;
+    if discriminant < 0.0 {
+        return -1.0;
+    }
+    let root = discriminant.sqrt();
+    ((b + root) / a).max((b - root) / a)
+}
+
+/// The color of a gradient at `offset` along it, in linear space.
+fn gradient_color(primitive: &Primitive, stops: &[GpuGradientStop], offset: f32) -> [f32; 4] {
+    let t = extend(offset, primitive.extend);
+
+    if stops.is_empty() {
+        return [0.0; 4];
+    }
+    // Out of range stops read the last uploaded one, like a bounds checked
+    // storage buffer read.
+    let stop = |i: u32| &stops[(i as usize).min(stops.len() - 1)];
+    let first = primitive.stops_start;
+    let last = first + primitive.stops_count.max(1) - 1;
+    let mut color = stop(first).color;
+    if t >= stop(last).pos {
+        color = stop(last).color;
+    }
+    for i in first..last {
+        let (a, b) = (stop(i), stop(i + 1));
+        if a.pos <= t && t < b.pos {
+            let f = (t - a.pos) / (b.pos - a.pos);
+            for (c, channel) in color.iter_mut().enumerate() {
+                *channel = a.color[c] + (b.color[c] - a.color[c]) * f;
+            }
+        }
+    }
+    [
+        srgb_to_linear(color[0]),
+        srgb_to_linear(color[1]),
+        srgb_to_linear(color[2]),
+        color[3],
+    ]
+}
+
+/// Map `t` into the gradient's range following the extend mode.
+fn extend(t: f32, mode: u32) -> f32 {
+    match mode {
+        EXTEND_REPEAT => t - t.floor(),
+        EXTEND_REFLECT => {
+            let half = t * 0.5;
+            1.0 - ((half - half.floor()) * 2.0 - 1.0).abs()
+        }
+        _ => t.clamp(0.0, 1.0),
+    }
+}
+
+fn erf(x: f32) -> f32 {
+    let s = x.signum();
+    let a = x.abs();
+    let mut r = 1.0 + (0.278393 + (0.230389 + 0.078108 * (a * a)) * a) * a;
+    r *= r;
+    s - s / (r * r)
+}
+
+fn box_shadow(lower: [f32; 2], upper: [f32; 2], point: [f32; 2], radius: f32) -> f32 {
+    let factor = 0.5f32.sqrt() / radius;
+    let integral = |x: f32| 0.5 + 0.5 * erf(x * factor);
+    (integral(point[0] - upper[0]) - integral(point[0] - lower[0]))
+        * (integral(point[1] - upper[1]) - integral(point[1] - lower[1]))
+}
+
+pub(crate) fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        ((x + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub(crate) fn linear_to_srgb(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        x * 12.92
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+fn to_unorm(x: f32) -> u8 {
+    (x.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// The values `shader/geometry.wgsl` computes for gradient and blur pixels,
+/// which the rasterizer has to reproduce.
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::EXTEND_PAD;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!(
+            (actual - expected).abs() < 1e-4,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    fn stop(pos: f32, color: [f32; 4]) -> GpuGradientStop {
+        GpuGradientStop {
+            color,
+            pos,
+            _pad: [0.0; 3],
+        }
+    }
+
+    #[test]
+    fn linear_gradient_projects_onto_its_line() {
+        let primitive = Primitive {
+            gradient: [10.0, 0.0, 10.0, 20.0],
+            ..Default::default()
+        };
+        assert_close(linear_gradient_t(&primitive, [10.0, 0.0]), 0.0);
+        assert_close(linear_gradient_t(&primitive, [-3.0, 5.0]), 0.25);
+        assert_close(linear_gradient_t(&primitive, [7.0, 30.0]), 1.5);
+        let degenerate = Primitive {
+            gradient: [1.0, 1.0, 1.0, 1.0],
+            ..Default::default()
+        };
+        assert_close(linear_gradient_t(&degenerate, [5.0, 5.0]), 0.0);
+    }
+
+    #[test]
+    fn radial_gradient_grows_from_the_focal_point() {
+        let centered = Primitive {
+            gradient: [0.0, 0.0, 0.0, 0.0],
+            gradient_radius: 10.0,
+            ..Default::default()
+        };
+        assert_close(radial_gradient_t(&centered, [0.0, 0.0]), 0.0);
+        assert_close(radial_gradient_t(&centered, [3.0, 4.0]), 0.5);
+        assert_close(radial_gradient_t(&centered, [0.0, -20.0]), 2.0);
+
+        let offset = Primitive {
+            gradient: [0.0, 0.0, 5.0, 0.0],
+            gradient_radius: 10.0,
+            ..Default::default()
+        };
+        assert_close(radial_gradient_t(&offset, [5.0, 0.0]), 0.0);
+        assert_close(radial_gradient_t(&offset, [10.0, 0.0]), 1.0);
+        assert_close(radial_gradient_t(&offset, [-10.0, 0.0]), 1.0);
+        assert_close(radial_gradient_t(&offset, [0.0, 10.0]), 1.0);
+
+        // A focal point outside the circle leaves a cone without a circle.
+        let outside = Primitive {
+            gradient: [0.0, 0.0, 20.0, 0.0],
+            gradient_radius: 10.0,
+            ..Default::default()
+        };
+        assert!(radial_gradient_t(&outside, [20.0, 15.0]) < 0.0);
+    }
+
+    #[test]
+    fn extend_modes_map_into_the_gradient() {
+        assert_close(extend(1.25, EXTEND_PAD), 1.0);
+        assert_close(extend(-0.5, EXTEND_PAD), 0.0);
+        assert_close(extend(1.25, EXTEND_REPEAT), 0.25);
+        assert_close(extend(-0.25, EXTEND_REPEAT), 0.75);
+        assert_close(extend(1.25, EXTEND_REFLECT), 0.75);
+        assert_close(extend(-0.25, EXTEND_REFLECT), 0.25);
+        assert_close(extend(2.25, EXTEND_REFLECT), 0.25);
+    }
+
+    #[test]
+    fn gradient_stops_mix_in_srgb_space() {
+        let stops = [
+            stop(0.0, [0.0, 0.0, 0.0, 1.0]),
+            stop(0.5, [1.0, 0.0, 0.0, 1.0]),
+            stop(1.0, [1.0, 1.0, 1.0, 0.0]),
+        ];
+        let primitive = Primitive {
+            stops_start: 0,
+            stops_count: 3,
+            extend: EXTEND_PAD,
+            ..Default::default()
+        };
+        // Halfway between black and red is 0.5 in sRGB.
+        let color = gradient_color(&primitive, &stops, 0.25);
+        assert_close(color[0], srgb_to_linear(0.5));
+        assert_close(color[1], 0.0);
+        assert_close(color[3], 1.0);
+        let color = gradient_color(&primitive, &stops, 0.75);
+        assert_close(color[1], srgb_to_linear(0.5));
+        assert_close(color[3], 0.5);
+        // Past the ends, the first and last stops.
+        assert_close(gradient_color(&primitive, &stops, -1.0)[0], 0.0);
+        assert_close(gradient_color(&primitive, &stops, 2.0)[3], 0.0);
+    }
+
+    #[test]
+    fn box_shadow_integrates_a_gaussian() {
+        assert_close(erf(0.0), 0.0);
+        // The shader's approximation is within 5e-4 of erf.
+        assert!((erf(1.0) - 0.842_700_8).abs() < 5e-4);
+        assert!((erf(-2.0) + 0.995_322_3).abs() < 5e-4);
+
+        let (lower, upper, radius) = ([0.0, 0.0], [40.0, 40.0], 2.0);
+        assert_close(box_shadow(lower, upper, [20.0, 20.0], radius), 1.0);
+        assert!((box_shadow(lower, upper, [0.0, 20.0], radius) - 0.5).abs() < 1e-3);
+        assert!((box_shadow(lower, upper, [0.0, 0.0], radius) - 0.25).abs() < 1e-3);
+        assert!(box_shadow(lower, upper, [-20.0, 20.0], radius) < 1e-4);
+        // One standard deviation inside the edge.
+        let inside = box_shadow(lower, upper, [radius, 20.0], radius);
+        assert!((inside - 0.841_344_7).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,71 @@
+//! Decoding encoded images (PNG, JPEG, WebP and the first frame of a GIF)
+//! into [`WgpuImage`]s.
+
+use std::io::Cursor;
+
+use ::image::{DynamicImage, ImageDecoder, ImageReader};
+use piet::ImageFormat;
+
+use crate::image::WgpuImage;
+
+/// Decode `data` into an image of at most `max_dimension` pixels a side.
+///
+/// The EXIF orientation is applied, and pixels are converted from the
+/// embedded ICC profile, if there is one, to sRGB.
+pub(crate) fn decode(
+    data: &[u8],
+    max_dimension: usize,
+    mipmaps: bool,
+) -> Result<WgpuImage, piet::Error> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .map_err(|e| piet::Error::BackendError(e.into()))?
+        .into_decoder()
+        .map_err(backend_error)?;
+    let orientation = decoder.orientation().map_err(backend_error)?;
+    let icc_profile = decoder.icc_profile().map_err(backend_error)?;
+
+    let mut image = DynamicImage::from_decoder(decoder).map_err(backend_error)?;
+    image.apply_orientation(orientation);
+
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let mut pixels = image.into_rgba8().into_raw();
+    if let Some(icc_profile) = icc_profile {
+        to_srgb(&icc_profile, &mut pixels);
+    }
+    WgpuImage::from_buf(
+        width,
+        height,
+        &pixels,
+        ImageFormat::RgbaSeparate,
+        max_dimension,
+        mipmaps,
+    )
+}
+
+/// Convert RGBA pixels in place from the color space described by
+/// `icc_profile` to sRGB. Profiles that can't be parsed or that aren't RGB
+/// profiles leave the pixels as they are.
+fn to_srgb(icc_profile: &[u8], pixels: &mut [u8]) {
+    let input = match qcms::Profile::new_from_slice(icc_profile, false) {
+        Some(profile) => profile,
+        None => return,
+    };
+    if input.is_sRGB() {
+        return;
+    }
+    let mut output = qcms::Profile::new_sRGB();
+    output.precache_output_transform();
+    if let Some(transform) = qcms::Transform::new(
+        &input,
+        &output,
+        qcms::DataType::RGBA8,
+        qcms::Intent::Perceptual,
+    ) {
+        transform.apply(pixels);
+    }
+}
+
+fn backend_error(e: ::image::ImageError) -> piet::Error {
+    piet::Error::BackendError(e.into())
+}
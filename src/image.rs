@@ -0,0 +1,569 @@
+//! Images, the brushes that paint with them and the textures they're drawn
+//! from.
+
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use hashbrown::HashMap;
+use piet::kurbo::{Affine, Rect, Size};
+use piet::{Image, ImageFormat, InterpolationMode};
+use wgpu::util::DeviceExt;
+
+use crate::context::ExtendMode;
+use crate::cpu::{linear_to_srgb, srgb_to_linear};
+use crate::pipeline::{EXTEND_PAD, EXTEND_REFLECT, EXTEND_REPEAT};
+
+/// The format of image textures. Their texels hold linear colors
+/// premultiplied with alpha, sRGB encoded, so they're filtered in linear space
+/// without dark fringes.
+pub(crate) const IMAGE_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+static NEXT_IMAGE_ID: AtomicU64 = AtomicU64::new(0);
+
+/// An image that can be drawn by any render context of the thread it was
+/// made on. Clones share their pixels.
+///
+/// The image keeps its pixels in memory for as long as it lives, as the CPU
+/// renderer draws from them and the GPU renderer uploads them again when its
+/// texture was evicted. Only the textures count towards
+/// [`WgpuRenderer::set_image_cache_budget`](crate::WgpuRenderer::set_image_cache_budget).
+#[derive(Clone)]
+pub struct WgpuImage {
+    /// Identifies the image's texture.
+    pub(crate) id: u64,
+    pub(crate) data: Rc<ImageData>,
+}
+
+/// The pixels of an image, in the layout of its texture: 8-bit RGBA rows in
+/// [`IMAGE_FORMAT`].
+pub(crate) struct ImageData {
+    pub(crate) width: usize,
+    pub(crate) height: usize,
+    /// The pixels of every mip level, from the largest.
+    pub(crate) pixels: Vec<u8>,
+    pub(crate) mip_level_count: u32,
+}
+
+impl ImageData {
+    /// An image without mipmaps.
+    pub(crate) fn new(width: usize, height: usize, pixels: Vec<u8>) -> Self {
+        Self {
+            width,
+            height,
+            pixels,
+            mip_level_count: 1,
+        }
+    }
+
+    /// The size and pixels of mip level `level`.
+    pub(crate) fn mip_level(&self, level: u32) -> (usize, usize, &[u8]) {
+        let (mut width, mut height, mut start) = (self.width, self.height, 0);
+        for _ in 0..level {
+            start += width * height * 4;
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+        }
+        (
+            width,
+            height,
+            &self.pixels[start..start + width * height * 4],
+        )
+    }
+
+    /// Add mip levels down to a single pixel, each averaging 2x2 pixels of
+    /// the one before it in linear space.
+    fn generate_mipmaps(&mut self, decode: &[f32]) {
+        self.pixels.truncate(self.width * self.height * 4);
+        self.mip_level_count = 1;
+        while self.width.max(self.height) >> self.mip_level_count > 0 {
+            let (width, height, pixels) = self.mip_level(self.mip_level_count - 1);
+            let (next_width, next_height) = ((width / 2).max(1), (height / 2).max(1));
+            let mut next = Vec::with_capacity(next_width * next_height * 4);
+            for y in 0..next_height {
+                for x in 0..next_width {
+                    let mut sum = [0.0; 4];
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let sx = (2 * x + dx).min(width - 1);
+                        let sy = (2 * y + dy).min(height - 1);
+                        let p = &pixels[(sy * width + sx) * 4..][..4];
+                        for c in 0..3 {
+                            sum[c] += decode[p[c] as usize];
+                        }
+                        sum[3] += p[3] as f32;
+                    }
+                    for &c in &sum[..3] {
+                        next.push((linear_to_srgb(c / 4.0) * 255.0).round() as u8);
+                    }
+                    next.push((sum[3] / 4.0).round() as u8);
+                }
+            }
+            self.pixels.extend_from_slice(&next);
+            self.mip_level_count += 1;
+        }
+    }
+}
+
+impl WgpuImage {
+    /// Make an image from `buf`, the sRGB pixels of an image in `format`, with
+    /// mipmaps if `mipmaps` is set.
+    ///
+    /// Fails with [`piet::Error::InvalidInput`] if a side is zero or `buf`
+    /// doesn't hold exactly `width` by `height` pixels, and with
+    /// [`piet::Error::NotSupported`] if a side is longer than `max_dimension`.
+    /// Textures can't be empty, so neither can images.
+    pub(crate) fn from_buf(
+        width: usize,
+        height: usize,
+        buf: &[u8],
+        format: ImageFormat,
+        max_dimension: usize,
+        mipmaps: bool,
+    ) -> Result<Self, piet::Error> {
+        let len = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(format.bytes_per_pixel()))
+            .ok_or(piet::Error::InvalidInput)?;
+        if len == 0 || buf.len() != len {
+            return Err(piet::Error::InvalidInput);
+        }
+        if width > max_dimension || height > max_dimension {
+            return Err(piet::Error::NotSupported);
+        }
+        let decode = srgb_decode_table();
+        let pixels = match format {
+            ImageFormat::Grayscale => buf.iter().flat_map(|&l| [l, l, l, 255]).collect(),
+            ImageFormat::Rgb => buf
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            ImageFormat::RgbaSeparate => premultiply(buf, &decode, false),
+            ImageFormat::RgbaPremul => premultiply(buf, &decode, true),
+            _ => return Err(piet::Error::NotSupported),
+        };
+        let mut data = ImageData::new(width, height, pixels);
+        if mipmaps {
+            data.generate_mipmaps(&decode);
+        }
+        Ok(Self::from_data(data))
+    }
+
+    pub(crate) fn from_data(data: ImageData) -> Self {
+        Self {
+            id: NEXT_IMAGE_ID.fetch_add(1, Ordering::Relaxed),
+            data: Rc::new(data),
+        }
+    }
+}
+
+/// Convert 8-bit RGBA sRGB pixels, premultiplied with alpha if
+/// `premultiplied` is set, into the pixels of an image texture.
+fn premultiply(buf: &[u8], decode: &[f32], premultiplied: bool) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(buf.len());
+    for pixel in buf.chunks_exact(4) {
+        let alpha = pixel[3];
+        match alpha {
+            0 => pixels.extend_from_slice(&[0; 4]),
+            255 => pixels.extend_from_slice(pixel),
+            _ => {
+                let a = alpha as f32 / 255.0;
+                for &c in &pixel[..3] {
+                    // Premultiplied colors are premultiplied in sRGB, and
+                    // have to be straightened before they're decoded.
+                    let straight = if premultiplied {
+                        srgb_to_linear((c as f32 / 255.0 / a).min(1.0))
+                    } else {
+                        decode[c as usize]
+                    };
+                    pixels.push((linear_to_srgb(straight * a) * 255.0).round() as u8);
+                }
+                pixels.push(alpha);
+            }
+        }
+    }
+    pixels
+}
+
+/// Maps an sRGB encoded channel to linear.
+fn srgb_decode_table() -> Vec<f32> {
+    (0..=255)
+        .map(|i| srgb_to_linear(i as f32 / 255.0))
+        .collect()
+}
+
+impl Image for WgpuImage {
+    fn size(&self) -> Size {
+        Size::new(self.data.width as f64, self.data.height as f64)
+    }
+}
+
+/// A brush that paints with an image, as a pattern of copies of it that can
+/// be stretched or tiled.
+#[derive(Clone)]
+pub struct ImageBrush {
+    pub(crate) image: WgpuImage,
+    pub(crate) transform: Affine,
+    pub(crate) extend: [ExtendMode; 2],
+    pub(crate) interpolation: InterpolationMode,
+    /// The area of the image, in its pixels, that sampling stays inside.
+    pub(crate) bounds: Option<Rect>,
+}
+
+impl ImageBrush {
+    /// Tile `image` at its size from the origin, with bilinear interpolation.
+    pub fn new(image: &WgpuImage) -> Self {
+        Self {
+            image: image.clone(),
+            transform: Affine::IDENTITY,
+            extend: [ExtendMode::Repeat, ExtendMode::Repeat],
+            interpolation: InterpolationMode::Bilinear,
+            bounds: None,
+        }
+    }
+
+    /// Place the image with `transform`, from its pixel coordinates into the
+    /// coordinates of what the brush paints.
+    pub fn with_transform(mut self, transform: Affine) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// How the pattern continues past the image horizontally and vertically.
+    /// [`ExtendMode::Pad`] on both stretches a single copy.
+    pub fn with_extend(mut self, x: ExtendMode, y: ExtendMode) -> Self {
+        self.extend = [x, y];
+        self
+    }
+
+    /// How the image is sampled between its pixels.
+    pub fn with_interpolation(mut self, interpolation: InterpolationMode) -> Self {
+        self.interpolation = interpolation;
+        self
+    }
+
+    /// Keep sampling inside `bounds`, in the image's pixels, so that pixels
+    /// around it don't bleed in.
+    pub(crate) fn with_bounds(mut self, bounds: Rect) -> Self {
+        self.bounds = Some(bounds);
+        self
+    }
+
+    /// The texture coordinates sampling is clamped to, as `[x0, y0, x1, y1]`.
+    /// They stop at the centers of the pixels on the edges of the bounds.
+    pub(crate) fn texture_bounds(&self) -> [f32; 4] {
+        let bounds = match self.bounds {
+            Some(bounds) => bounds,
+            None => return [f32::MIN, f32::MIN, f32::MAX, f32::MAX],
+        };
+        let size = self.image.size();
+        let axis = |start: f64, end: f64, len: f64| {
+            let (start, end) = (start + 0.5, end - 0.5);
+            // Bounds thinner than a pixel are clamped to their middle.
+            let (start, end) = if start > end {
+                let middle = (start + end) / 2.0;
+                (middle, middle)
+            } else {
+                (start, end)
+            };
+            ((start / len) as f32, (end / len) as f32)
+        };
+        let (x0, x1) = axis(bounds.x0, bounds.x1, size.width.max(1.0));
+        let (y0, y1) = axis(bounds.y0, bounds.y1, size.height.max(1.0));
+        [x0, y0, x1, y1]
+    }
+
+    /// How the brush's draw commands sample the image, which is at `index` in
+    /// the frame's images.
+    pub(crate) fn binding(&self, index: usize) -> ImageBinding {
+        ImageBinding {
+            image: index,
+            extend: [extend_value(self.extend[0]), extend_value(self.extend[1])],
+            filter: match self.interpolation {
+                InterpolationMode::NearestNeighbor => wgpu::FilterMode::Nearest,
+                InterpolationMode::Bilinear => wgpu::FilterMode::Linear,
+            },
+        }
+    }
+}
+
+/// The [`Primitive::extend`](crate::pipeline::Primitive::extend) value of
+/// `extend`.
+pub(crate) fn extend_value(extend: ExtendMode) -> u32 {
+    match extend {
+        ExtendMode::Pad => EXTEND_PAD,
+        ExtendMode::Repeat => EXTEND_REPEAT,
+        ExtendMode::Reflect => EXTEND_REFLECT,
+    }
+}
+
+/// An image a draw command paints with and how it's sampled.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ImageBinding {
+    /// The index of the image in the frame's images.
+    pub(crate) image: usize,
+    /// How the image is extended horizontally and vertically, one of the
+    /// `EXTEND_*` values.
+    pub(crate) extend: [u32; 2],
+    pub(crate) filter: wgpu::FilterMode,
+}
+
+/// How an image is extended horizontally and vertically, and filtered.
+type SamplerKey = ([u32; 2], wgpu::FilterMode);
+
+struct ImageTexture {
+    _texture: wgpu::Texture,
+    view: wgpu::TextureView,
+    /// The bind groups sampling the texture, kept until it's dropped.
+    bind_groups: HashMap<SamplerKey, Rc<wgpu::BindGroup>>,
+    /// Dropped with the last clone of the image, which drops the texture.
+    image: Weak<ImageData>,
+    /// The size of the texture's pixels, all mip levels included.
+    bytes: usize,
+    /// The frame the texture was last drawn in.
+    last_drawn: u64,
+}
+
+/// Statistics of a renderer's image texture cache, from
+/// [`WgpuRenderer::image_cache_stats`](crate::WgpuRenderer::image_cache_stats).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ImageCacheStats {
+    /// The size of the image textures on the GPU.
+    pub bytes_resident: usize,
+    /// How many times an image was drawn with its texture already on the GPU.
+    pub hits: u64,
+    /// How many times an image's texture had to be uploaded to draw it.
+    pub misses: u64,
+    /// How many textures were dropped to stay within the budget.
+    pub evictions: u64,
+}
+
+/// The textures of the images drawn by a renderer, and the samplers they're
+/// drawn with.
+///
+/// Textures stay on the GPU until their image is dropped, or until they're
+/// the least recently drawn ones when a new texture wouldn't fit the budget.
+pub(crate) struct ImageStore {
+    textures: HashMap<u64, ImageTexture>,
+    samplers: HashMap<SamplerKey, wgpu::Sampler>,
+    pub(crate) budget: Option<usize>,
+    pub(crate) stats: ImageCacheStats,
+    frame: u64,
+}
+
+impl ImageStore {
+    pub(crate) fn new() -> Self {
+        Self {
+            textures: HashMap::new(),
+            samplers: HashMap::new(),
+            budget: None,
+            stats: ImageCacheStats::default(),
+            frame: 0,
+        }
+    }
+
+    /// Start uploading a frame: drop the textures of images that are gone.
+    pub(crate) fn begin_frame(&mut self) {
+        self.frame += 1;
+        let stats = &mut self.stats;
+        self.textures.retain(|_, texture| {
+            let alive = texture.image.strong_count() > 0;
+            if !alive {
+                stats.bytes_resident -= texture.bytes;
+            }
+            alive
+        });
+    }
+
+    /// The bind group that samples `image` the way `binding` says, uploading
+    /// its texture if it doesn't have one yet.
+    pub(crate) fn bind_group(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+        image: &WgpuImage,
+        binding: &ImageBinding,
+    ) -> Rc<wgpu::BindGroup> {
+        if self.textures.contains_key(&image.id) {
+            self.stats.hits += 1;
+        } else {
+            self.stats.misses += 1;
+            self.make_room(image.data.pixels.len());
+            let texture = upload(device, queue, image);
+            self.stats.bytes_resident += texture.bytes;
+            self.textures.insert(image.id, texture);
+        }
+        let texture = self.textures.get_mut(&image.id).unwrap();
+        texture.last_drawn = self.frame;
+        let key = (binding.extend, binding.filter);
+        let samplers = &mut self.samplers;
+        let view = &texture.view;
+        texture
+            .bind_groups
+            .entry(key)
+            .or_insert_with(|| {
+                let sampler = samplers.entry(key).or_insert_with(|| {
+                    device.create_sampler(&wgpu::SamplerDescriptor {
+                        label: Some("Image sampler"),
+                        address_mode_u: address_mode(binding.extend[0]),
+                        address_mode_v: address_mode(binding.extend[1]),
+                        address_mode_w: wgpu::AddressMode::ClampToEdge,
+                        mag_filter: binding.filter,
+                        min_filter: binding.filter,
+                        mipmap_filter: binding.filter,
+                        // Only bilinear interpolation picks a mip level.
+                        lod_max_clamp: match binding.filter {
+                            wgpu::FilterMode::Nearest => 0.0,
+                            wgpu::FilterMode::Linear => f32::MAX,
+                        },
+                        ..Default::default()
+                    })
+                });
+                Rc::new(image_bind_group(device, layout, view, sampler))
+            })
+            .clone()
+    }
+
+    /// Evict the least recently drawn textures until `bytes` more fit the
+    /// budget. Textures drawn in this frame are kept, so a frame drawing more
+    /// than the budget goes over it.
+    fn make_room(&mut self, bytes: usize) {
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => return,
+        };
+        let mut candidates: Vec<(u64, u64)> = self
+            .textures
+            .iter()
+            .filter(|(_, texture)| texture.last_drawn < self.frame)
+            .map(|(id, texture)| (texture.last_drawn, *id))
+            .collect();
+        candidates.sort_unstable();
+        for (_, id) in candidates {
+            if self.stats.bytes_resident + bytes <= budget {
+                break;
+            }
+            let texture = self.textures.remove(&id).unwrap();
+            self.stats.bytes_resident -= texture.bytes;
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+fn upload(device: &wgpu::Device, queue: &wgpu::Queue, image: &WgpuImage) -> ImageTexture {
+    let data = &image.data;
+    let texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: Some("Image texture"),
+            size: wgpu::Extent3d {
+                width: data.width as u32,
+                height: data.height as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: data.mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: IMAGE_FORMAT,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        },
+        &data.pixels,
+    );
+    let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+    ImageTexture {
+        _texture: texture,
+        view,
+        bind_groups: HashMap::new(),
+        image: Rc::downgrade(&image.data),
+        bytes: data.pixels.len(),
+        last_drawn: 0,
+    }
+}
+
+fn address_mode(extend: u32) -> wgpu::AddressMode {
+    match extend {
+        EXTEND_REPEAT => wgpu::AddressMode::Repeat,
+        EXTEND_REFLECT => wgpu::AddressMode::MirrorRepeat,
+        _ => wgpu::AddressMode::ClampToEdge,
+    }
+}
+
+/// The layout of the bind group images are sampled from.
+pub(crate) fn image_bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+    device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+        label: Some("Image bind group layout"),
+        entries: &[
+            wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Texture {
+                    sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                    multisampled: false,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 1,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                count: None,
+            },
+        ],
+    })
+}
+
+/// A bind group for draws without an image, sampling a transparent texel.
+pub(crate) fn empty_image_bind_group(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> wgpu::BindGroup {
+    let texture = upload(
+        device,
+        queue,
+        &WgpuImage::from_data(ImageData::new(1, 1, vec![0; 4])),
+    );
+    let sampler = device.create_sampler(&wgpu::SamplerDescriptor::default());
+    image_bind_group(device, layout, &texture.view, &sampler)
+}
+
+fn image_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    view: &wgpu::TextureView,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Image bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+        ],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use piet::{ImageFormat, RenderContext};
+
+    use crate::{Piet, WgpuRenderer};
+
+    #[test]
+    fn images_without_pixels_are_rejected() {
+        let mut renderer = WgpuRenderer::new_cpu();
+        let mut rc = Piet::new(&mut renderer);
+        for (width, height) in [(0, 5), (5, 0), (0, 0)] {
+            let image = rc.make_image(width, height, &[], ImageFormat::RgbaSeparate);
+            assert!(matches!(image, Err(piet::Error::InvalidInput)));
+        }
+        let image = rc.make_image_with_mipmaps(0, 5, &[], ImageFormat::Grayscale);
+        assert!(matches!(image, Err(piet::Error::InvalidInput)));
+    }
+}
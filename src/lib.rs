@@ -1,7 +1,13 @@
+mod blit;
 mod context;
+mod cpu;
+#[cfg(feature = "decode")]
+mod decode;
 mod font;
+mod image;
 mod layer;
 mod pipeline;
+mod stroke;
 mod svg;
 mod text;
 mod transformation;
@@ -13,15 +19,21 @@ pub use piet::*;
 pub use svg::Svg;
 use svg::SvgStore;
 
-use std::{cell::RefCell, marker::PhantomData, rc::Rc};
+use std::{cell::RefCell, marker::PhantomData, num::NonZeroU32, rc::Rc};
 
-use context::{WgpuImage, WgpuRenderContext};
-use text::{WgpuText, WgpuTextLayout, WgpuTextLayoutBuilder};
+use context::WgpuRenderContext;
+use futures::task::SpawnExt;
+use pipeline::FrameData;
+use text::{TextUpload, WgpuText, WgpuTextLayout, WgpuTextLayoutBuilder};
 
 pub type Piet<'a> = WgpuRenderContext<'a>;
 
 pub type Brush = context::Brush;
 
+pub use context::ExtendMode;
+
+pub use image::{ImageBrush, ImageCacheStats, WgpuImage};
+
 pub type PietText = WgpuText;
 
 pub type PietTextLayout = WgpuTextLayout;
@@ -31,21 +43,33 @@ pub type PietTextLayoutBuilder = WgpuTextLayoutBuilder;
 pub type PietImage = WgpuImage;
 
 pub struct WgpuRenderer {
-    instance: wgpu::Instance,
-    device: Rc<wgpu::Device>,
-    surface: wgpu::Surface,
-    queue: wgpu::Queue,
-    format: wgpu::TextureFormat,
-    staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
-    local_pool: futures::executor::LocalPool,
-    msaa: wgpu::TextureView,
+    pub(crate) backend: Backend,
     size: Size,
     svg_store: SvgStore,
 
     text: WgpuText,
+}
+
+/// What a `WgpuRenderer` draws its frames with.
+pub(crate) enum Backend {
+    Gpu(Box<Gpu>),
+    Cpu(cpu::Rasterizer),
+}
+
+pub(crate) struct Gpu {
+    instance: Option<wgpu::Instance>,
+    pub(crate) device: Rc<wgpu::Device>,
+    target: RenderTarget,
+    queue: Rc<wgpu::Queue>,
+    format: wgpu::TextureFormat,
+    staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
+    local_pool: futures::executor::LocalPool,
+    pub(crate) msaa: wgpu::TextureView,
+    pub(crate) stencil: wgpu::TextureView,
 
-    pipeline: pipeline::Pipeline,
-    pub(crate) encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+    pub(crate) pipeline: pipeline::Pipeline,
+    pub(crate) blit: blit::Blit,
+    encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
 }
 
 impl WgpuRenderer {
@@ -62,15 +86,103 @@ impl WgpuRenderer {
             .ok_or(piet::Error::NotSupported)?;
         info!("{:?}", adapter.get_info());
 
+        let format = surface
+            .get_preferred_format(&adapter)
+            .ok_or(piet::Error::MissingFeature("no supported texture format"))?;
+
+        Self::from_adapter(instance, &adapter, format, Some(surface))
+    }
+
+    /// Create a renderer that isn't attached to any window.
+    ///
+    /// Frames are rendered into an offscreen RGBA texture, which is sized by
+    /// [`set_size`](Self::set_size) and can be read back with
+    /// [`read_pixels`](Self::read_pixels). If no hardware adapter is available
+    /// a software fallback adapter is used instead, and if there's no adapter
+    /// at all the frames are rasterized on the CPU like with
+    /// [`new_cpu`](Self::new_cpu).
+    pub fn new_headless() -> Result<Self, piet::Error> {
+        let backend = wgpu::util::backend_bits_from_env().unwrap_or_else(wgpu::Backends::all);
+        let instance = wgpu::Instance::new(backend);
+        let adapter = [false, true].iter().find_map(|&force_fallback_adapter| {
+            futures::executor::block_on(instance.request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::HighPerformance,
+                compatible_surface: None,
+                force_fallback_adapter,
+            }))
+        });
+        let adapter = match adapter {
+            Some(adapter) => adapter,
+            None => {
+                info!("no adapter found, rasterizing on the CPU");
+                return Ok(Self::new_cpu());
+            }
+        };
+        info!("{:?}", adapter.get_info());
+
+        Self::from_adapter(instance, &adapter, HEADLESS_FORMAT, None)
+    }
+
+    /// Create a headless renderer that rasterizes on the CPU.
+    ///
+    /// It draws the same tessellated geometry as the GPU pipeline, following
+    /// the rules of its shader, and doesn't need any adapter.
+    /// [`WgpuRenderContext::finish_into`] isn't supported.
+    pub fn new_cpu() -> Self {
+        Self {
+            backend: Backend::Cpu(cpu::Rasterizer::new()),
+            size: Size::ZERO,
+            svg_store: SvgStore::new(),
+            text: WgpuText::new(None),
+        }
+    }
+
+    /// Create a renderer that shares a device and queue with the caller.
+    ///
+    /// The renderer doesn't own any surface. Finish frames with
+    /// [`WgpuRenderContext::finish_into`] to draw them as a layer into a
+    /// texture view of `format` that the caller acquires and presents.
+    pub fn from_device(
+        device: Rc<wgpu::Device>,
+        queue: Rc<wgpu::Queue>,
+        format: wgpu::TextureFormat,
+    ) -> Self {
+        let target = RenderTarget::Texture(create_target_texture(&device, format, 1, 1));
+        Self::from_parts(None, device, queue, format, target)
+    }
+
+    fn from_adapter(
+        instance: wgpu::Instance,
+        adapter: &wgpu::Adapter,
+        format: wgpu::TextureFormat,
+        surface: Option<wgpu::Surface>,
+    ) -> Result<Self, piet::Error> {
         let (device, queue) = futures::executor::block_on(
             adapter.request_device(&wgpu::DeviceDescriptor::default(), None),
         )
         .map_err(|e| piet::Error::BackendError(Box::new(e)))?;
 
-        let format = surface
-            .get_preferred_format(&adapter)
-            .ok_or(piet::Error::MissingFeature("no supported texture format"))?;
+        let target = match surface {
+            Some(surface) => RenderTarget::Surface(surface),
+            None => RenderTarget::Texture(create_target_texture(&device, format, 1, 1)),
+        };
 
+        Ok(Self::from_parts(
+            Some(instance),
+            Rc::new(device),
+            Rc::new(queue),
+            format,
+            target,
+        ))
+    }
+
+    fn from_parts(
+        instance: Option<wgpu::Instance>,
+        device: Rc<wgpu::Device>,
+        queue: Rc<wgpu::Queue>,
+        format: wgpu::TextureFormat,
+        target: RenderTarget,
+    ) -> Self {
         let staging_belt = wgpu::util::StagingBelt::new(1024);
         let local_pool = futures::executor::LocalPool::new();
 
@@ -88,40 +200,257 @@ impl WgpuRenderer {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
         });
         let msaa = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let stencil = create_stencil_view(&device, 1, 1);
 
         let staging_belt = Rc::new(RefCell::new(staging_belt));
         let encoder = Rc::new(RefCell::new(None));
-        let device = Rc::new(device);
-        let text = WgpuText::new(device.clone(), staging_belt.clone(), encoder.clone());
-        let pipeline = pipeline::Pipeline::new(&device, format, &text.cache.borrow());
-
-        Ok(Self {
-            instance,
-            device,
-            queue,
-            surface,
+        let text = WgpuText::new(Some(TextUpload {
+            device: device.clone(),
+            staging_belt: staging_belt.clone(),
+            encoder: encoder.clone(),
+        }));
+        let pipeline = pipeline::Pipeline::new(&device, &queue, format, &text.cache.borrow());
+        let blit = blit::Blit::new(&device, format);
+
+        Self {
+            backend: Backend::Gpu(Box::new(Gpu {
+                instance,
+                device,
+                queue,
+                target,
+                format,
+                staging_belt,
+                local_pool,
+                msaa,
+                stencil,
+                pipeline,
+                blit,
+                encoder,
+            })),
             text,
             size: Size::ZERO,
-            format,
-            staging_belt,
-            local_pool,
-            msaa,
-            pipeline,
             svg_store: SvgStore::new(),
-            encoder,
-        })
+        }
+    }
+
+    /// Whether frames are rasterized on the CPU, because the renderer was
+    /// made with [`new_cpu`](Self::new_cpu) or no adapter was found.
+    pub fn is_cpu(&self) -> bool {
+        matches!(self.backend, Backend::Cpu(_))
     }
 
     pub fn set_size(&mut self, size: Size) {
         self.size = size;
-        let sc_desc = wgpu::SurfaceConfiguration {
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            format: self.format,
-            width: size.width as u32,
-            height: size.height as u32,
-            present_mode: wgpu::PresentMode::Fifo,
-        };
-        self.surface.configure(&self.device, &sc_desc);
+        match &mut self.backend {
+            Backend::Gpu(gpu) => gpu.set_size(size),
+            Backend::Cpu(rasterizer) => rasterizer.set_size(size),
+        }
+    }
+
+    pub fn set_scale(&mut self, scale: f64) {
+        match &mut self.backend {
+            Backend::Gpu(gpu) => gpu.pipeline.scale = scale,
+            Backend::Cpu(rasterizer) => rasterizer.scale = scale,
+        }
+        self.text.cache.borrow_mut().scale = scale;
+    }
+
+    /// The scale from logical coordinates to physical pixels.
+    pub fn scale(&self) -> f64 {
+        match &self.backend {
+            Backend::Gpu(gpu) => gpu.pipeline.scale,
+            Backend::Cpu(rasterizer) => rasterizer.scale,
+        }
+    }
+
+    /// Limit the GPU memory used by image textures to `budget` bytes, or lift
+    /// the limit with `None`, which is the default.
+    ///
+    /// When a texture wouldn't fit, the textures drawn least recently are
+    /// dropped and uploaded again when they're next drawn. Textures drawn in
+    /// the same frame are never dropped for each other. The CPU renderer keeps
+    /// no textures, so this does nothing there.
+    ///
+    /// Only the textures are bounded: every image also keeps its pixels in
+    /// memory until it's dropped, so they can be uploaded again.
+    pub fn set_image_cache_budget(&mut self, budget: Option<usize>) {
+        if let Backend::Gpu(gpu) = &mut self.backend {
+            gpu.pipeline.images.budget = budget;
+        }
+    }
+
+    /// Statistics of the image texture cache.
+    pub fn image_cache_stats(&self) -> ImageCacheStats {
+        match &self.backend {
+            Backend::Gpu(gpu) => gpu.pipeline.images.stats,
+            Backend::Cpu(_) => ImageCacheStats::default(),
+        }
+    }
+
+    /// The largest width or height an image can have.
+    pub(crate) fn max_image_dimension(&self) -> usize {
+        match &self.backend {
+            Backend::Gpu(gpu) => gpu.device.limits().max_texture_dimension_2d as usize,
+            Backend::Cpu(_) => usize::MAX,
+        }
+    }
+
+    /// Decode a PNG, JPEG, WebP or GIF image, of which only the first frame is
+    /// used.
+    ///
+    /// The image's EXIF orientation is applied and its colors are converted
+    /// to sRGB from its embedded ICC profile, if it has one.
+    #[cfg(feature = "decode")]
+    pub fn decode_image(&self, data: &[u8]) -> Result<WgpuImage, piet::Error> {
+        decode::decode(data, self.max_image_dimension(), false)
+    }
+
+    /// Decode an image like [`decode_image`](Self::decode_image), and generate
+    /// mipmaps for it like
+    /// [`make_image_with_mipmaps`](WgpuRenderContext::make_image_with_mipmaps)
+    /// does.
+    #[cfg(feature = "decode")]
+    pub fn decode_image_with_mipmaps(&self, data: &[u8]) -> Result<WgpuImage, piet::Error> {
+        decode::decode(data, self.max_image_dimension(), true)
+    }
+
+    pub fn text(&self) -> WgpuText {
+        self.text.clone()
+    }
+
+    /// Draw a frame's geometry onto the current frame, starting the pass with
+    /// `load`.
+    pub(crate) fn draw(
+        &mut self,
+        frame: &FrameData,
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> Result<(), piet::Error> {
+        match &mut self.backend {
+            Backend::Gpu(gpu) => {
+                let mut encoder = gpu.upload(frame);
+                let target = gpu.current_frame()?;
+
+                gpu.pipeline.draw(
+                    &mut encoder,
+                    &target.view,
+                    &gpu.msaa,
+                    &gpu.stencil,
+                    frame.commands,
+                    load,
+                );
+
+                gpu.submit(encoder);
+                target.present();
+            }
+            Backend::Cpu(rasterizer) => {
+                if let wgpu::LoadOp::Clear(_) = load {
+                    rasterizer.clear();
+                }
+                rasterizer.draw(frame, &self.text.cache.borrow());
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw what a frame has so far, without presenting it, and read back the
+    /// pixels of `rect`, which is `[x, y, width, height]` in physical pixels
+    /// inside the frame. They're returned like [`read_pixels`](Self::read_pixels)
+    /// returns them. The pass starts with `load`, like [`draw`](Self::draw).
+    pub(crate) fn capture(
+        &mut self,
+        frame: &FrameData,
+        rect: [u32; 4],
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> Result<Vec<u8>, piet::Error> {
+        let [x, y, width, height] = rect.map(|v| v as usize);
+        if x + width > self.size.width as usize || y + height > self.size.height as usize {
+            return Err(piet::Error::InvalidInput);
+        }
+        match &mut self.backend {
+            Backend::Gpu(gpu) => gpu.capture(frame, self.size, rect, load),
+            Backend::Cpu(rasterizer) => {
+                if let wgpu::LoadOp::Clear(_) = load {
+                    rasterizer.clear();
+                }
+                rasterizer.draw(frame, &self.text.cache.borrow());
+                let pixels = rasterizer.read_pixels();
+                let stride = self.size.width as usize * 4;
+                Ok((y..y + height)
+                    .flat_map(|row| &pixels[row * stride + x * 4..row * stride + (x + width) * 4])
+                    .copied()
+                    .collect())
+            }
+        }
+    }
+
+    /// Read back the last finished frame.
+    ///
+    /// The pixels are returned row by row without padding, as 8-bit RGBA with
+    /// premultiplied alpha.
+    pub fn read_pixels(&mut self) -> Result<Vec<u8>, piet::Error> {
+        match &mut self.backend {
+            Backend::Gpu(gpu) => gpu.read_pixels(self.size),
+            Backend::Cpu(rasterizer) => Ok(rasterizer.read_pixels()),
+        }
+    }
+
+    /// Read back the last finished frame into an [`ImageBuf`].
+    ///
+    /// Rows in the returned buffer are tightly packed, so the stride is
+    /// `width * format.bytes_per_pixel()`. `Rgb` drops alpha, leaving colors
+    /// as they'd look over black, and `Grayscale` is the luma of the
+    /// unpremultiplied colors.
+    pub fn to_image_buf(&mut self, format: ImageFormat) -> Result<ImageBuf, piet::Error> {
+        let pixels = convert_pixels(self.read_pixels()?, format)?;
+        Ok(ImageBuf::from_raw(
+            pixels,
+            format,
+            self.size.width as usize,
+            self.size.height as usize,
+        ))
+    }
+
+    /// Save the last finished frame to a PNG file.
+    #[cfg(feature = "png")]
+    pub fn save_to_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<(), piet::Error> {
+        let pixels = convert_pixels(self.read_pixels()?, ImageFormat::RgbaSeparate)?;
+        let file = std::fs::File::create(path).map_err(|e| piet::Error::BackendError(e.into()))?;
+        let mut encoder = png::Encoder::new(
+            std::io::BufWriter::new(file),
+            self.size.width as u32,
+            self.size.height as u32,
+        );
+        encoder.set_color(png::ColorType::RGBA);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder
+            .write_header()
+            .and_then(|mut writer| writer.write_image_data(&pixels))
+            .map_err(|e| piet::Error::BackendError(e.into()))
+    }
+}
+
+impl Gpu {
+    fn set_size(&mut self, size: Size) {
+        match &mut self.target {
+            RenderTarget::Surface(surface) => {
+                let sc_desc = wgpu::SurfaceConfiguration {
+                    usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+                    format: self.format,
+                    width: size.width as u32,
+                    height: size.height as u32,
+                    present_mode: wgpu::PresentMode::Fifo,
+                };
+                surface.configure(&self.device, &sc_desc);
+            }
+            RenderTarget::Texture(texture) => {
+                *texture = create_target_texture(
+                    &self.device,
+                    self.format,
+                    size.width as u32,
+                    size.height as u32,
+                );
+            }
+        }
         let msaa_texture = self.device.create_texture(&wgpu::TextureDescriptor {
             label: Some("Multisampled frame descriptor"),
             size: wgpu::Extent3d {
@@ -136,19 +465,37 @@ impl WgpuRenderer {
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
         });
         self.msaa = msaa_texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.stencil = create_stencil_view(&self.device, size.width as u32, size.height as u32);
         self.pipeline.size = size;
     }
 
-    pub fn set_scale(&mut self, scale: f64) {
-        self.pipeline.scale = scale;
-        self.text.cache.borrow_mut().scale = scale;
+    /// Upload a frame's geometry, returning the encoder holding the copies.
+    pub(crate) fn upload(&mut self, frame: &FrameData) -> wgpu::CommandEncoder {
+        self.ensure_encoder();
+        let mut encoder = self.take_encoder();
+
+        self.pipeline.upload_data(
+            &self.device,
+            &self.queue,
+            &mut self.staging_belt.borrow_mut(),
+            &mut encoder,
+            frame,
+        );
+        encoder
     }
 
-    pub fn text(&self) -> WgpuText {
-        self.text.clone()
+    pub(crate) fn submit(&mut self, encoder: wgpu::CommandEncoder) {
+        self.staging_belt.borrow_mut().finish();
+        self.queue.submit(Some(encoder.finish()));
+
+        self.local_pool
+            .spawner()
+            .spawn(self.staging_belt.borrow_mut().recall())
+            .expect("Recall staging belt");
+        self.local_pool.run_until_stalled();
     }
 
-    pub(crate) fn ensure_encoder(&mut self) {
+    fn ensure_encoder(&mut self) {
         let mut encoder = self.encoder.borrow_mut();
         if encoder.is_none() {
             *encoder = Some(
@@ -160,9 +507,277 @@ impl WgpuRenderer {
         }
     }
 
-    pub(crate) fn take_encoder(&mut self) -> wgpu::CommandEncoder {
+    fn take_encoder(&mut self) -> wgpu::CommandEncoder {
         self.encoder.take().unwrap()
     }
+
+    pub(crate) fn offscreen_view(&self) -> Option<wgpu::TextureView> {
+        match &self.target {
+            RenderTarget::Texture(texture) => {
+                Some(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+            }
+            RenderTarget::Surface(_) => None,
+        }
+    }
+
+    fn current_frame(&self) -> Result<Frame, piet::Error> {
+        match &self.target {
+            RenderTarget::Surface(surface) => {
+                let texture = surface
+                    .get_current_texture()
+                    .map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+                let view = texture
+                    .texture
+                    .create_view(&wgpu::TextureViewDescriptor::default());
+                Ok(Frame {
+                    surface_texture: Some(texture),
+                    view,
+                })
+            }
+            RenderTarget::Texture(texture) => Ok(Frame {
+                surface_texture: None,
+                view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            }),
+        }
+    }
+
+    /// Draw `frame` into the multisampled frame, and read back `rect` of it.
+    fn capture(
+        &mut self,
+        frame: &FrameData,
+        size: Size,
+        rect: [u32; 4],
+        load: wgpu::LoadOp<wgpu::Color>,
+    ) -> Result<Vec<u8>, piet::Error> {
+        let mut encoder = self.upload(frame);
+        // The multisampled frame keeps what's drawn, so it can be resolved
+        // somewhere else than the frame and be resolved again when the frame
+        // is finished.
+        let resolved = create_target_texture(
+            &self.device,
+            self.format,
+            size.width as u32,
+            size.height as u32,
+        );
+        let view = resolved.create_view(&wgpu::TextureViewDescriptor::default());
+        self.pipeline.draw(
+            &mut encoder,
+            &view,
+            &self.msaa,
+            &self.stencil,
+            frame.commands,
+            load,
+        );
+        self.submit(encoder);
+
+        let encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("capture"),
+            });
+        self.copy_pixels(encoder, &resolved, rect)
+    }
+
+    fn read_pixels(&mut self, size: Size) -> Result<Vec<u8>, piet::Error> {
+        let width = size.width as u32;
+        let height = size.height as u32;
+
+        let mut encoder = self
+            .device
+            .create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("readback"),
+            });
+
+        let resolved;
+        let texture = match &self.target {
+            RenderTarget::Texture(texture) => texture,
+            RenderTarget::Surface(_) => {
+                // Surface textures can't be copied from, but the multisampled
+                // frame is still around, so resolve it again into a texture
+                // that can.
+                resolved = create_target_texture(&self.device, self.format, width, height);
+                let view = resolved.create_view(&wgpu::TextureViewDescriptor::default());
+                let _ = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                    label: None,
+                    color_attachments: &[wgpu::RenderPassColorAttachment {
+                        view: &self.msaa,
+                        resolve_target: Some(&view),
+                        ops: wgpu::Operations {
+                            load: wgpu::LoadOp::Load,
+                            store: true,
+                        },
+                    }],
+                    depth_stencil_attachment: None,
+                });
+                &resolved
+            }
+        };
+        self.copy_pixels(encoder, texture, [0, 0, width, height])
+    }
+
+    /// Submit `encoder` along with a copy of `rect` of `texture`, which is
+    /// `[x, y, width, height]`, and read the copy back.
+    fn copy_pixels(
+        &self,
+        mut encoder: wgpu::CommandEncoder,
+        texture: &wgpu::Texture,
+        rect: [u32; 4],
+    ) -> Result<Vec<u8>, piet::Error> {
+        let [x, y, width, height] = rect;
+
+        // Rows copied into a buffer have to be aligned to
+        // wgpu::COPY_BYTES_PER_ROW_ALIGNMENT, so they are unpadded afterwards.
+        let unpadded_bytes_per_row = width * 4;
+        let align = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT;
+        let padded_bytes_per_row =
+            unpadded_bytes_per_row + (align - unpadded_bytes_per_row % align) % align;
+
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback buffer"),
+            size: padded_bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        encoder.copy_texture_to_buffer(
+            wgpu::ImageCopyTexture {
+                texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d { x, y, z: 0 },
+                aspect: wgpu::TextureAspect::All,
+            },
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: NonZeroU32::new(padded_bytes_per_row),
+                    rows_per_image: NonZeroU32::new(height),
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        self.queue.submit(Some(encoder.finish()));
+
+        let slice = buffer.slice(..);
+        let mapping = slice.map_async(wgpu::MapMode::Read);
+        self.device.poll(wgpu::Maintain::Wait);
+        futures::executor::block_on(mapping).map_err(|e| piet::Error::BackendError(Box::new(e)))?;
+
+        let mut pixels = Vec::with_capacity((unpadded_bytes_per_row * height) as usize);
+        {
+            let data = slice.get_mapped_range();
+            for row in data.chunks(padded_bytes_per_row as usize) {
+                pixels.extend_from_slice(&row[..unpadded_bytes_per_row as usize]);
+            }
+        }
+        buffer.unmap();
+
+        if let wgpu::TextureFormat::Bgra8Unorm | wgpu::TextureFormat::Bgra8UnormSrgb = self.format {
+            for pixel in pixels.chunks_exact_mut(4) {
+                pixel.swap(0, 2);
+            }
+        }
+
+        Ok(pixels)
+    }
+}
+
+/// Convert 8-bit premultiplied RGBA pixels into `format`.
+fn convert_pixels(mut pixels: Vec<u8>, format: ImageFormat) -> Result<Vec<u8>, piet::Error> {
+    match format {
+        ImageFormat::RgbaPremul => Ok(pixels),
+        ImageFormat::RgbaSeparate => {
+            for pixel in pixels.chunks_exact_mut(4) {
+                let a = pixel[3];
+                pixel[0] = piet::util::unpremul(pixel[0], a);
+                pixel[1] = piet::util::unpremul(pixel[1], a);
+                pixel[2] = piet::util::unpremul(pixel[2], a);
+            }
+            Ok(pixels)
+        }
+        ImageFormat::Rgb => Ok(pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| pixel[..3].iter().copied())
+            .collect()),
+        // The Rec. 709 luma of the unpremultiplied sRGB color.
+        ImageFormat::Grayscale => Ok(pixels
+            .chunks_exact(4)
+            .map(|pixel| {
+                let [r, g, b] = [0, 1, 2].map(|i| piet::util::unpremul(pixel[i], pixel[3]));
+                (0.2126 * r as f32 + 0.7152 * g as f32 + 0.0722 * b as f32).round() as u8
+            })
+            .collect()),
+        _ => Err(piet::Error::NotSupported),
+    }
+}
+
+/// The format of the offscreen texture used by headless renderers.
+const HEADLESS_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba8UnormSrgb;
+
+/// Where a `WgpuRenderer` puts its finished frames.
+pub(crate) enum RenderTarget {
+    Surface(wgpu::Surface),
+    Texture(wgpu::Texture),
+}
+
+/// The texture a single frame is resolved into.
+pub(crate) struct Frame {
+    surface_texture: Option<wgpu::SurfaceTexture>,
+    pub(crate) view: wgpu::TextureView,
+}
+
+impl Frame {
+    pub(crate) fn present(self) {
+        if let Some(texture) = self.surface_texture {
+            texture.present();
+        }
+    }
+}
+
+fn create_target_texture(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen frame texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT
+            | wgpu::TextureUsages::COPY_SRC
+            | wgpu::TextureUsages::TEXTURE_BINDING,
+    })
+}
+
+/// The multisampled stencil buffer frames draw their clips into.
+fn create_stencil_view(device: &wgpu::Device, width: u32, height: u32) -> wgpu::TextureView {
+    device
+        .create_texture(&wgpu::TextureDescriptor {
+            label: Some("Multisampled stencil texture"),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 4,
+            dimension: wgpu::TextureDimension::D2,
+            format: pipeline::STENCIL_FORMAT,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        })
+        .create_view(&wgpu::TextureViewDescriptor::default())
 }
 
 pub struct Device {
@@ -173,7 +788,77 @@ pub struct Device {
 
 unsafe impl Send for Device {}
 
+impl Device {
+    /// Create a new device.
+    pub fn new() -> Result<Device, piet::Error> {
+        Ok(Device {
+            marker: PhantomData,
+        })
+    }
+
+    /// Create a new bitmap target.
+    ///
+    /// `width` and `height` are in pixels, drawing is scaled by `pix_scale`.
+    pub fn bitmap_target(
+        &mut self,
+        width: usize,
+        height: usize,
+        pix_scale: f64,
+    ) -> Result<BitmapTarget<'_>, piet::Error> {
+        let mut renderer = WgpuRenderer::new_headless()?;
+        renderer.set_size(Size::new(width as f64, height as f64));
+        renderer.set_scale(pix_scale);
+        Ok(BitmapTarget {
+            renderer,
+            phantom: PhantomData,
+        })
+    }
+}
+
 /// A struct provides a `RenderContext` and then can have its bitmap extracted.
 pub struct BitmapTarget<'a> {
+    renderer: WgpuRenderer,
     phantom: PhantomData<&'a ()>,
 }
+
+impl<'a> BitmapTarget<'a> {
+    /// Get a piet `RenderContext` for the bitmap.
+    ///
+    /// Note: caller is responsible for calling `finish` on the render
+    /// context at the end of rendering.
+    pub fn render_context(&mut self) -> WgpuRenderContext<'_> {
+        WgpuRenderContext::new(&mut self.renderer)
+    }
+
+    /// Get the finished pixels as 8-bit RGBA with premultiplied alpha.
+    pub fn raw_pixels(&mut self) -> Result<Vec<u8>, piet::Error> {
+        self.renderer.read_pixels()
+    }
+
+    /// Copy the finished pixels into `buf`, converted to `format`.
+    ///
+    /// Returns the number of bytes written.
+    pub fn copy_raw_pixels(
+        &mut self,
+        format: ImageFormat,
+        buf: &mut [u8],
+    ) -> Result<usize, piet::Error> {
+        let pixels = convert_pixels(self.renderer.read_pixels()?, format)?;
+        if buf.len() < pixels.len() {
+            return Err(piet::Error::InvalidInput);
+        }
+        buf[..pixels.len()].copy_from_slice(&pixels);
+        Ok(pixels.len())
+    }
+
+    /// Get an in-memory image buffer of the finished pixels.
+    pub fn to_image_buf(&mut self, format: ImageFormat) -> Result<ImageBuf, piet::Error> {
+        self.renderer.to_image_buf(format)
+    }
+
+    /// Save the finished pixels to a PNG file.
+    #[cfg(feature = "png")]
+    pub fn save_to_file<P: AsRef<std::path::Path>>(mut self, path: P) -> Result<(), piet::Error> {
+        self.renderer.save_to_file(path)
+    }
+}
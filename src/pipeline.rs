@@ -1,10 +1,13 @@
 use std::hash::BuildHasherDefault;
 use std::num::{NonZeroU32, NonZeroU64};
+use std::ops::Range;
+use std::rc::Rc;
 use std::sync::Arc;
 
 use font_kit::canvas::{Canvas, Format, RasterizationOptions};
 use font_kit::family_name::FamilyName;
 use font_kit::font::Font;
+use font_kit::handle::Handle;
 use font_kit::hinting::HintingOptions;
 use font_kit::loader::Loader;
 use font_kit::source::SystemSource;
@@ -19,13 +22,35 @@ use lyon::lyon_tessellation::{
 use lyon::tessellation;
 use pathfinder_geometry::transform2d::Transform2F;
 use pathfinder_geometry::vector::{Vector2F, Vector2I};
-use piet::kurbo::{Affine, Point, Rect, Size};
+use piet::kurbo::{Affine, Point, Rect, Size, Vec2};
 use piet::{Color, FontFamily, FontWeight};
 use wgpu::util::DeviceExt;
 
+use crate::image::{empty_image_bind_group, image_bind_group_layout, ImageBinding, ImageStore};
+use crate::WgpuImage;
+
 const FONTS_DIR: Dir = include_dir!("./fonts");
 const DEFAULT_FONT: &[u8] = include_bytes!("../fonts/CascadiaCode-Regular.otf");
 
+/// How many primitives the primitives buffer has room for at first. It grows
+/// for frames that use more.
+const INITIAL_PRIMITIVES: usize = 1000;
+
+/// How many gradient stops the gradient stops buffer has room for at first.
+/// It grows for frames that use more.
+const INITIAL_GRADIENT_STOPS: usize = 4096;
+
+/// Values of [`Primitive::paint`].
+pub(crate) const PAINT_SOLID: u32 = 0;
+pub(crate) const PAINT_LINEAR_GRADIENT: u32 = 1;
+pub(crate) const PAINT_RADIAL_GRADIENT: u32 = 2;
+pub(crate) const PAINT_IMAGE: u32 = 3;
+
+/// Values of [`Primitive::extend`].
+pub(crate) const EXTEND_PAD: u32 = 0;
+pub(crate) const EXTEND_REPEAT: u32 = 1;
+pub(crate) const EXTEND_REFLECT: u32 = 2;
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 struct Globals {
@@ -40,14 +65,31 @@ unsafe impl bytemuck::Zeroable for Globals {}
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct Primitive {
-    pub(crate) clip_rect: [f32; 4],
     pub(crate) transform_1: [f32; 4],
     pub(crate) blur_rect: [f32; 4],
     pub(crate) transform_2: [f32; 2],
     pub(crate) translate: [f32; 2],
     pub(crate) scale: [f32; 2],
-    pub(crate) clip: f32,
     pub(crate) blur_radius: f32,
+    /// What the triangles are painted with, multiplied with the vertex color.
+    pub(crate) paint: u32,
+    /// The start and end of a linear gradient, or the center and focal point
+    /// of a radial one, in the coordinates of the vertices.
+    pub(crate) gradient: [f32; 4],
+    /// The range of the gradient's stops in the frame's stops.
+    pub(crate) stops_start: u32,
+    pub(crate) stops_count: u32,
+    pub(crate) extend: u32,
+    /// The radius of a radial gradient.
+    pub(crate) gradient_radius: f32,
+    /// Maps the coordinates of the vertices to the texture coordinates of an
+    /// image paint.
+    pub(crate) image_transform_1: [f32; 4],
+    /// The texture coordinates an image paint is clamped to, as
+    /// `[x0, y0, x1, y1]`.
+    pub(crate) image_bounds: [f32; 4],
+    pub(crate) image_transform_2: [f32; 2],
+    pub(crate) _pad: [f32; 2],
 }
 
 unsafe impl bytemuck::Pod for Primitive {}
@@ -58,12 +100,20 @@ impl Default for Primitive {
         Self {
             translate: [0.0, 0.0],
             scale: [1.0, 1.0],
-            clip: 0.0,
-            clip_rect: [0.0, 0.0, 0.0, 0.0],
             transform_1: [1.0, 0.0, 0.0, 1.0],
             transform_2: [0.0, 0.0],
             blur_rect: [0.0, 0.0, 0.0, 0.0],
             blur_radius: 0.0,
+            paint: PAINT_SOLID,
+            gradient: [0.0, 0.0, 0.0, 0.0],
+            stops_start: 0,
+            stops_count: 0,
+            extend: EXTEND_PAD,
+            gradient_radius: 0.0,
+            image_transform_1: [1.0, 0.0, 0.0, 1.0],
+            image_bounds: [f32::MIN, f32::MIN, f32::MAX, f32::MAX],
+            image_transform_2: [0.0, 0.0],
+            _pad: [0.0, 0.0],
         }
     }
 }
@@ -95,14 +145,72 @@ impl Default for GpuVertex {
     }
 }
 
+/// A gradient stop, with its color in sRGB.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GpuGradientStop {
+    pub(crate) color: [f32; 4],
+    pub(crate) pos: f32,
+    pub(crate) _pad: [f32; 3],
+}
+
+unsafe impl bytemuck::Pod for GpuGradientStop {}
+unsafe impl bytemuck::Zeroable for GpuGradientStop {}
+
+/// The format of the stencil buffer clips are drawn into.
+pub(crate) const STENCIL_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth24PlusStencil8;
+
+/// A range of a frame's indices and how it's drawn.
+///
+/// Clips are kept in the stencil buffer, which holds how many of the clips
+/// around a sample contain it. Content is drawn where that's the number of
+/// clips it's drawn under.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum DrawCommand {
+    /// Draw triangles under `depth` clips, painting the ones with an image
+    /// paint with `image`.
+    Draw {
+        indices: Range<u32>,
+        depth: u32,
+        image: Option<ImageBinding>,
+    },
+    /// Intersect the clip at `depth - 1` with the shape of the triangles.
+    PushClip { indices: Range<u32>, depth: u32 },
+    /// Undo the `PushClip` of the same triangles.
+    PopClip { indices: Range<u32>, depth: u32 },
+}
+
+/// What a render context records for a frame.
+pub(crate) struct FrameData<'a> {
+    pub(crate) geometry: &'a VertexBuffers<GpuVertex, u32>,
+    pub(crate) primitives: &'a [Primitive],
+    pub(crate) gradient_stops: &'a [GpuGradientStop],
+    /// The images the draw commands paint with.
+    pub(crate) images: &'a [WgpuImage],
+    pub(crate) commands: &'a [DrawCommand],
+}
+
 pub struct Pipeline {
     pub pipeline: wgpu::RenderPipeline,
+    push_clip: wgpu::RenderPipeline,
+    pop_clip: wgpu::RenderPipeline,
+    bind_group_layout: wgpu::BindGroupLayout,
     bind_group: wgpu::BindGroup,
+    sampler: wgpu::Sampler,
+    glyph_view: wgpu::TextureView,
+    image_bind_group_layout: wgpu::BindGroupLayout,
+    /// Bound for draws without an image.
+    empty_image_bind_group: wgpu::BindGroup,
+    /// The image bind group of each of the frame's draw commands.
+    image_bind_groups: Vec<Option<Rc<wgpu::BindGroup>>>,
+    pub(crate) images: ImageStore,
     globals: wgpu::Buffer,
     primitives: wgpu::Buffer,
+    gradient_stops: wgpu::Buffer,
     vertices: wgpu::Buffer,
     indices: wgpu::Buffer,
     supported_primitives: usize,
+    supported_gradient_stops: usize,
     supported_vertices: usize,
     supported_indices: usize,
     pub(crate) size: Size,
@@ -110,11 +218,17 @@ pub struct Pipeline {
 }
 
 impl Pipeline {
-    pub fn new(device: &wgpu::Device, format: wgpu::TextureFormat, cache: &Cache) -> Self {
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        cache: &Cache,
+    ) -> Self {
         let globals_buffer_byte_size = std::mem::size_of::<Globals>() as u64;
-        let supported_primitives = 1000;
         let primitives_buffer_byte_size =
-            std::mem::size_of::<Primitive>() as u64 * supported_primitives as u64;
+            std::mem::size_of::<Primitive>() as u64 * INITIAL_PRIMITIVES as u64;
+        let gradient_stops_buffer_byte_size =
+            std::mem::size_of::<GpuGradientStop>() as u64 * INITIAL_GRADIENT_STOPS as u64;
 
         let globals = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Globals ubo"),
@@ -128,6 +242,12 @@ impl Pipeline {
             usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
+        let gradient_stops = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Gradient stops"),
+            size: gradient_stops_buffer_byte_size,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
 
         let vertices = device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Globals ubo"),
@@ -191,7 +311,7 @@ impl Pipeline {
                 },
                 wgpu::BindGroupLayoutEntry {
                     binding: 3,
-                    visibility: wgpu::ShaderStages::VERTEX,
+                    visibility: wgpu::ShaderStages::VERTEX | wgpu::ShaderStages::FRAGMENT,
                     ty: wgpu::BindingType::Buffer {
                         ty: wgpu::BufferBindingType::Storage { read_only: true },
                         has_dynamic_offset: false,
@@ -199,94 +319,144 @@ impl Pipeline {
                     },
                     count: None,
                 },
-            ],
-        });
-
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("Bind group"),
-            layout: &bind_group_layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::Buffer(globals.as_entire_buffer_binding()),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&cache.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Buffer(primitives.as_entire_buffer_binding()),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 4,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: wgpu::BufferSize::new(gradient_stops_buffer_byte_size),
+                    },
+                    count: None,
                 },
             ],
         });
 
+        let glyph_view = cache
+            .texture
+            .as_ref()
+            .expect("glyph cache texture")
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = create_bind_group(
+            device,
+            &bind_group_layout,
+            &sampler,
+            &glyph_view,
+            [&globals, &primitives, &gradient_stops],
+        );
+
+        let image_bind_group_layout = image_bind_group_layout(device);
+        let empty_image_bind_group =
+            empty_image_bind_group(device, queue, &image_bind_group_layout);
+
         let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-            bind_group_layouts: &[&bind_group_layout],
+            bind_group_layouts: &[&bind_group_layout, &image_bind_group_layout],
             push_constant_ranges: &[],
             label: Some("pipeline layout"),
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("pipeline descriptor"),
-            layout: Some(&pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<GpuVertex>() as u64,
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &wgpu::vertex_attr_array!(
-                        0 => Float32x2,
-                        1 => Float32x2,
-                        2 => Float32x4,
-                        3 => Float32,
-                        4 => Float32x2,
-                        5 => Uint32,
-                    ),
-                }],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[wgpu::ColorTargetState {
-                    format,
-                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
-                    write_mask: wgpu::ColorWrites::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                polygon_mode: wgpu::PolygonMode::Fill,
-                front_face: wgpu::FrontFace::Ccw,
-                strip_index_format: None,
-                cull_mode: None,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: None,
-            multisample: wgpu::MultisampleState {
-                count: 4,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
+        let create_pipeline = |label, stencil: wgpu::StencilFaceState, write_mask| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: Some(label),
+                layout: Some(&pipeline_layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point: "vs_main",
+                    buffers: &[wgpu::VertexBufferLayout {
+                        array_stride: std::mem::size_of::<GpuVertex>() as u64,
+                        step_mode: wgpu::VertexStepMode::Vertex,
+                        attributes: &wgpu::vertex_attr_array!(
+                            0 => Float32x2,
+                            1 => Float32x2,
+                            2 => Float32x4,
+                            3 => Float32,
+                            4 => Float32x2,
+                            5 => Uint32,
+                        ),
+                    }],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fs_main",
+                    targets: &[wgpu::ColorTargetState {
+                        format,
+                        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                        write_mask,
+                    }],
+                }),
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    front_face: wgpu::FrontFace::Ccw,
+                    strip_index_format: None,
+                    cull_mode: None,
+                    unclipped_depth: false,
+                    conservative: false,
+                },
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: STENCIL_FORMAT,
+                    depth_write_enabled: false,
+                    depth_compare: wgpu::CompareFunction::Always,
+                    stencil: wgpu::StencilState {
+                        front: stencil,
+                        back: stencil,
+                        read_mask: !0,
+                        write_mask: !0,
+                    },
+                    bias: wgpu::DepthBiasState::default(),
+                }),
+                multisample: wgpu::MultisampleState {
+                    count: 4,
+                    mask: !0,
+                    alpha_to_coverage_enabled: false,
+                },
+                multiview: None,
+            })
+        };
+        let stencil = |pass_op| wgpu::StencilFaceState {
+            compare: wgpu::CompareFunction::Equal,
+            fail_op: wgpu::StencilOperation::Keep,
+            depth_fail_op: wgpu::StencilOperation::Keep,
+            pass_op,
+        };
+        let pipeline = create_pipeline(
+            "pipeline descriptor",
+            stencil(wgpu::StencilOperation::Keep),
+            wgpu::ColorWrites::ALL,
+        );
+        let push_clip = create_pipeline(
+            "push clip pipeline",
+            stencil(wgpu::StencilOperation::IncrementClamp),
+            wgpu::ColorWrites::empty(),
+        );
+        let pop_clip = create_pipeline(
+            "pop clip pipeline",
+            stencil(wgpu::StencilOperation::DecrementClamp),
+            wgpu::ColorWrites::empty(),
+        );
 
         Self {
             pipeline,
+            push_clip,
+            pop_clip,
+            bind_group_layout,
             bind_group,
+            sampler,
+            glyph_view,
+            image_bind_group_layout,
+            empty_image_bind_group,
+            image_bind_groups: Vec::new(),
+            images: ImageStore::new(),
             globals,
             vertices,
             indices,
             primitives,
+            gradient_stops,
             supported_vertices: 1,
             supported_indices: 1,
-            supported_primitives,
+            supported_primitives: INITIAL_PRIMITIVES,
+            supported_gradient_stops: INITIAL_GRADIENT_STOPS,
             size: Size::ZERO,
             scale: 1.0,
         }
@@ -295,11 +465,51 @@ impl Pipeline {
     pub fn upload_data(
         &mut self,
         device: &wgpu::Device,
+        queue: &wgpu::Queue,
         staging_belt: &mut wgpu::util::StagingBelt,
         encoder: &mut wgpu::CommandEncoder,
-        geometry: &VertexBuffers<GpuVertex, u32>,
-        primitives: &[Primitive],
+        frame: &FrameData,
     ) {
+        let FrameData {
+            geometry,
+            primitives,
+            gradient_stops,
+            images,
+            commands,
+        } = *frame;
+        let mut storage_grown = false;
+        if primitives.len() > self.supported_primitives {
+            self.supported_primitives = primitives.len();
+            let size = std::mem::size_of::<Primitive>() as u64 * self.supported_primitives as u64;
+            self.primitives = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Pritives ubo"),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            storage_grown = true;
+        }
+        if gradient_stops.len() > self.supported_gradient_stops {
+            self.supported_gradient_stops = gradient_stops.len();
+            let size = std::mem::size_of::<GpuGradientStop>() as u64
+                * self.supported_gradient_stops as u64;
+            self.gradient_stops = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Gradient stops"),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            storage_grown = true;
+        }
+        if storage_grown {
+            self.bind_group = create_bind_group(
+                device,
+                &self.bind_group_layout,
+                &self.sampler,
+                &self.glyph_view,
+                [&self.globals, &self.primitives, &self.gradient_stops],
+            );
+        }
         if geometry.vertices.len() > self.supported_vertices {
             self.supported_vertices = geometry.vertices.len();
             let size = std::mem::size_of::<GpuVertex>() as u64 * self.supported_vertices as u64;
@@ -363,16 +573,7 @@ impl Pipeline {
         }
 
         {
-            if primitives.len() > self.supported_primitives {
-                println!(
-                    "warning primities len {} more than supported",
-                    primitives.len()
-                );
-            }
-
-            let primitives_bytes = bytemuck::cast_slice(
-                &primitives[..primitives.len().min(self.supported_primitives)],
-            );
+            let primitives_bytes = bytemuck::cast_slice(primitives);
             let mut primivites_buffer = staging_belt.write_buffer(
                 encoder,
                 &self.primitives,
@@ -382,18 +583,48 @@ impl Pipeline {
             );
             primivites_buffer.copy_from_slice(primitives_bytes);
         }
+
+        if !gradient_stops.is_empty() {
+            let stops_bytes = bytemuck::cast_slice(gradient_stops);
+            let mut stops_buffer = staging_belt.write_buffer(
+                encoder,
+                &self.gradient_stops,
+                0,
+                unsafe { NonZeroU64::new_unchecked(stops_bytes.len() as u64) },
+                device,
+            );
+            stops_buffer.copy_from_slice(stops_bytes);
+        }
+
+        self.images.begin_frame();
+        self.image_bind_groups.clear();
+        for command in commands {
+            let bind_group = match command {
+                DrawCommand::Draw {
+                    image: Some(binding),
+                    ..
+                } => Some(self.images.bind_group(
+                    device,
+                    queue,
+                    &self.image_bind_group_layout,
+                    &images[binding.image],
+                    binding,
+                )),
+                _ => None,
+            };
+            self.image_bind_groups.push(bind_group);
+        }
     }
 
     pub fn draw(
         &mut self,
-        device: &wgpu::Device,
         encoder: &mut wgpu::CommandEncoder,
         view: &wgpu::TextureView,
         msaa: &wgpu::TextureView,
-        geometry: &VertexBuffers<GpuVertex, u32>,
+        stencil: &wgpu::TextureView,
+        commands: &[DrawCommand],
+        load: wgpu::LoadOp<wgpu::Color>,
     ) {
-        let fill_range = 0..(geometry.indices.len() as u32);
-
         {
             let _ = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: None,
@@ -415,24 +646,83 @@ impl Pipeline {
                 color_attachments: &[wgpu::RenderPassColorAttachment {
                     view: msaa,
                     resolve_target: Some(&view),
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Load,
-                        store: true,
-                    },
+                    ops: wgpu::Operations { load, store: true },
                 }],
-                depth_stencil_attachment: None,
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: stencil,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: false,
+                    }),
+                    stencil_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(0),
+                        store: false,
+                    }),
+                }),
             });
 
-            pass.set_pipeline(&self.pipeline);
             pass.set_bind_group(0, &self.bind_group, &[]);
             pass.set_vertex_buffer(0, self.vertices.slice(..));
             pass.set_index_buffer(self.indices.slice(..), wgpu::IndexFormat::Uint32);
 
-            pass.draw_indexed(fill_range.clone(), 0, 0..1);
+            for (i, command) in commands.iter().enumerate() {
+                let (pipeline, indices, reference) = match command {
+                    DrawCommand::Draw { indices, depth, .. } => (&self.pipeline, indices, *depth),
+                    DrawCommand::PushClip { indices, depth } => {
+                        (&self.push_clip, indices, depth - 1)
+                    }
+                    DrawCommand::PopClip { indices, depth } => (&self.pop_clip, indices, *depth),
+                };
+                let image_bind_group = match self.image_bind_groups.get(i) {
+                    Some(Some(bind_group)) => bind_group,
+                    _ => &self.empty_image_bind_group,
+                };
+                pass.set_pipeline(pipeline);
+                pass.set_bind_group(1, image_bind_group, &[]);
+                pass.set_stencil_reference(reference);
+                pass.draw_indexed(indices.clone(), 0, 0..1);
+            }
         }
     }
 }
 
+/// The bind group of the globals, the glyph atlas and the storage buffers,
+/// made again whenever one of the buffers grows.
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    sampler: &wgpu::Sampler,
+    glyph_view: &wgpu::TextureView,
+    [globals, primitives, gradient_stops]: [&wgpu::Buffer; 3],
+) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("Bind group"),
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(globals.as_entire_buffer_binding()),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(glyph_view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::Buffer(primitives.as_entire_buffer_binding()),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::Buffer(gradient_stops.as_entire_buffer_binding()),
+            },
+        ],
+    })
+}
+
 #[derive(Debug, Clone, Hash, PartialEq, Eq, Default)]
 pub(crate) struct GlyphInfo {
     font_id: usize,
@@ -452,32 +742,14 @@ pub(crate) struct GlyphMetricInfo {
 pub(crate) struct GlyphPosInfo {
     pub(crate) info: GlyphInfo,
     pub(crate) metric: GlyphMetricInfo,
+    /// The glyph's advance, unshaped.
     pub(crate) width: f64,
+    /// The glyph's pixels, relative to where it's drawn from at the top of
+    /// the line.
     pub(crate) rect: Rect,
     pub(crate) cache_rect: Rect,
 }
 
-impl GlyphPosInfo {
-    pub fn empty(width: f64) -> Self {
-        GlyphPosInfo {
-            info: GlyphInfo {
-                font_id: 0,
-                glyph_id: 0,
-                font_size: 0,
-            },
-            metric: GlyphMetricInfo {
-                ascent: 0.0,
-                descent: 0.0,
-                line_gap: 0.0,
-                mono: false,
-            },
-            width: width,
-            rect: Size::new(width, 0.0).to_rect(),
-            cache_rect: Rect::ZERO,
-        }
-    }
-}
-
 struct Row {
     y: u32,
     height: u32,
@@ -485,32 +757,71 @@ struct Row {
     glyphs: Vec<GlyphPosInfo>,
 }
 
-pub struct Cache {
+/// The glyph atlas texture, for renderers that have a device.
+pub(crate) struct CacheTexture {
     texture: wgpu::Texture,
-    pub(super) view: wgpu::TextureView,
     upload_buffer: wgpu::Buffer,
     upload_buffer_size: u64,
-    width: u32,
-    height: u32,
+}
+
+/// What's needed to copy newly rasterized glyphs into the atlas texture.
+pub(crate) struct GlyphUpload<'a> {
+    pub(crate) device: &'a wgpu::Device,
+    pub(crate) staging_belt: &'a mut wgpu::util::StagingBelt,
+    pub(crate) encoder: &'a mut wgpu::CommandEncoder,
+}
+
+pub struct Cache {
+    pub(crate) texture: Option<CacheTexture>,
+    /// The atlas in memory, for the CPU rasterizer. Empty if there's a texture.
+    pub(crate) pixels: Vec<u8>,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
 
     font_source: SystemSource,
-    fonts: Vec<Font>,
-    default_font: Font,
+    fonts: Vec<CachedFont>,
+    default_font: CachedFont,
     fallback_fonts_range: std::ops::Range<usize>,
     fallback_fonts_loaded: bool,
     font_families: HashMap<(FontFamily, FontWeight), usize>,
 
     rows: LinkedHashMap<usize, Row>,
     glyphs: HashMap<GlyphInfo, (usize, usize)>,
-    glyph_infos: HashMap<(char, FontFamily, FontWeight), (usize, u32)>,
     pub(crate) scale: f64,
 }
 
-fn get_fallback_fonts() -> Vec<Font> {
+/// A font glyphs are shaped with and rasterized from.
+#[derive(Clone)]
+struct CachedFont {
+    font: Font,
+    /// The font file and the index of the font in it, for the shaper.
+    data: Arc<Vec<u8>>,
+    index: u32,
+}
+
+impl CachedFont {
+    fn new(font: Font, index: u32) -> Self {
+        let data = font.copy_font_data().unwrap_or_default();
+        Self { font, data, index }
+    }
+}
+
+/// A glyph shaped from a run of text.
+#[derive(Clone, Debug)]
+pub(crate) struct ShapedGlyph {
+    pub(crate) glyph_id: u32,
+    /// The byte offset in the run of the cluster the glyph belongs to.
+    pub(crate) cluster: usize,
+    pub(crate) x_advance: f64,
+    pub(crate) x_offset: f64,
+    pub(crate) y_offset: f64,
+}
+
+fn get_fallback_fonts() -> Vec<CachedFont> {
     let mut fonts = Vec::new();
     for file in FONTS_DIR.files() {
         if let Ok(font) = Font::from_bytes(Arc::new(file.contents().to_vec()), 0) {
-            fonts.push(font);
+            fonts.push(CachedFont::new(font, 0));
         }
     }
     fonts
@@ -519,37 +830,47 @@ fn get_fallback_fonts() -> Vec<Font> {
 impl Cache {
     const INITIAL_UPLOAD_BUFFER_SIZE: u64 = wgpu::COPY_BYTES_PER_ROW_ALIGNMENT as u64 * 100;
 
-    pub fn new(device: &wgpu::Device, width: u32, height: u32) -> Cache {
-        let texture = device.create_texture(&wgpu::TextureDescriptor {
-            label: Some("wgpu_glyph::Cache"),
-            size: wgpu::Extent3d {
-                width,
-                height,
-                depth_or_array_layers: 1,
-            },
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::R8Unorm,
-            usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
-            mip_level_count: 1,
-            sample_count: 1,
-        });
+    pub fn new(device: Option<&wgpu::Device>, width: u32, height: u32) -> Cache {
+        let texture = device.map(|device| {
+            let texture = device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("wgpu_glyph::Cache"),
+                size: wgpu::Extent3d {
+                    width,
+                    height,
+                    depth_or_array_layers: 1,
+                },
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::R8Unorm,
+                usage: wgpu::TextureUsages::COPY_DST | wgpu::TextureUsages::TEXTURE_BINDING,
+                mip_level_count: 1,
+                sample_count: 1,
+            });
 
-        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+            let upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("wgpu_glyph::Cache upload buffer"),
+                size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
+                usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
 
-        let upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
-            label: Some("wgpu_glyph::Cache upload buffer"),
-            size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
-            usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
-            mapped_at_creation: false,
+            CacheTexture {
+                texture,
+                upload_buffer,
+                upload_buffer_size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
+            }
         });
 
+        let pixels = if texture.is_some() {
+            Vec::new()
+        } else {
+            vec![0; width as usize * height as usize]
+        };
+
         let default_font = Font::from_bytes(Arc::new(DEFAULT_FONT.to_vec()), 0).unwrap();
 
         Cache {
             texture,
-            view,
-            upload_buffer,
-            upload_buffer_size: Self::INITIAL_UPLOAD_BUFFER_SIZE,
+            pixels,
             width,
             height,
 
@@ -557,18 +878,18 @@ impl Cache {
 
             font_families: HashMap::new(),
             fonts: Vec::new(),
-            default_font,
+            default_font: CachedFont::new(default_font, 0),
             fallback_fonts_range: 0..0,
             fallback_fonts_loaded: false,
 
             rows: LinkedHashMap::new(),
             glyphs: HashMap::new(),
-            glyph_infos: HashMap::new(),
             scale: 1.0,
         }
     }
 
-    fn get_glyph_from_fallback_fonts(&mut self, c: char) -> Option<(usize, u32)> {
+    /// The first of the bundled fallback fonts that has a glyph for `c`.
+    pub(crate) fn get_fallback_font(&mut self, c: char) -> Option<usize> {
         if !self.fallback_fonts_loaded {
             self.fallback_fonts_loaded = true;
             let mut fallback_fonts = get_fallback_fonts();
@@ -577,64 +898,108 @@ impl Cache {
             self.fonts.append(&mut fallback_fonts);
             self.fallback_fonts_range = start..end;
         }
-        if self.fallback_fonts_range.clone().count() == 0 {
-            return None;
-        }
 
-        for font_id in self.fallback_fonts_range.clone() {
-            let font = &self.fonts[font_id];
-            if let Some(glyph_id) = font.glyph_for_char(c) {
-                return Some((font_id, glyph_id));
-            }
-        }
-        None
+        self.fallback_fonts_range
+            .clone()
+            .find(|font_id| self.has_glyph(*font_id, c))
     }
 
-    fn get_glyph_info(
-        &mut self,
-        c: char,
-        font_family: FontFamily,
-        font_weight: FontWeight,
-        font_size: u32,
-    ) -> Result<GlyphInfo, piet::Error> {
-        let key = (c, font_family.clone(), font_weight);
-        if !self.glyph_infos.contains_key(&key) {
-            let font_id = self.get_font_by_family(font_family.clone(), font_weight);
-            let font = &self.fonts[font_id];
-
-            let (font_id, glyph_id) = if let Some(glyph_id) = font.glyph_for_char(c) {
-                (font_id, glyph_id)
-            } else {
-                self.get_glyph_from_fallback_fonts(c)
-                    .ok_or(piet::Error::MissingFont)?
-            };
+    pub(crate) fn has_glyph(&self, font_id: usize, c: char) -> bool {
+        self.fonts[font_id].font.glyph_for_char(c).is_some()
+    }
 
-            self.glyph_infos.insert(key.clone(), (font_id, glyph_id));
+    /// Shape `text` with a font, in `script` or in the one it's guessed to be
+    /// in. The glyphs are returned in visual order, which is right to left if
+    /// `rtl`.
+    pub(crate) fn shape(
+        &self,
+        font_id: usize,
+        font_size: f32,
+        text: &str,
+        script: Option<rustybuzz::Script>,
+        rtl: bool,
+    ) -> Vec<ShapedGlyph> {
+        let font = &self.fonts[font_id];
+        let face = match rustybuzz::Face::from_slice(&font.data, font.index) {
+            Some(face) => face,
+            None => {
+                let mut glyphs = self.shape_unshaped(font_id, font_size, text);
+                if rtl {
+                    glyphs.reverse();
+                }
+                return glyphs;
+            }
+        };
+
+        let mut buffer = rustybuzz::UnicodeBuffer::new();
+        buffer.push_str(text);
+        if let Some(script) = script {
+            buffer.set_script(script);
         }
+        buffer.set_direction(if rtl {
+            rustybuzz::Direction::RightToLeft
+        } else {
+            rustybuzz::Direction::LeftToRight
+        });
+        buffer.guess_segment_properties();
+
+        let output = rustybuzz::shape(&face, &[], buffer);
+        let units = font_size as f64 / face.units_per_em() as f64;
+        output
+            .glyph_infos()
+            .iter()
+            .zip(output.glyph_positions())
+            .map(|(info, position)| ShapedGlyph {
+                glyph_id: info.glyph_id,
+                cluster: info.cluster as usize,
+                x_advance: position.x_advance as f64 * units,
+                x_offset: position.x_offset as f64 * units,
+                y_offset: position.y_offset as f64 * units,
+            })
+            .collect()
+    }
 
-        let (font_id, glyph_id) = self.glyph_infos.get(&key).unwrap();
+    /// Map `text` to glyphs one character at a time, for fonts the shaper
+    /// can't read.
+    fn shape_unshaped(&self, font_id: usize, font_size: f32, text: &str) -> Vec<ShapedGlyph> {
+        let font = &self.fonts[font_id].font;
+        let units = font_size as f64 / font.metrics().units_per_em as f64;
+        text.char_indices()
+            .map(|(cluster, c)| {
+                let glyph_id = font.glyph_for_char(c).unwrap_or(0);
+                ShapedGlyph {
+                    glyph_id,
+                    cluster,
+                    x_advance: font.advance(glyph_id).map(|a| a.x()).unwrap_or(0.0) as f64 * units,
+                    x_offset: 0.0,
+                    y_offset: 0.0,
+                }
+            })
+            .collect()
+    }
 
-        Ok(GlyphInfo {
-            font_id: *font_id,
-            font_size,
-            glyph_id: *glyph_id,
-        })
+    /// The metrics of a font at a size.
+    pub(crate) fn get_font_metric(&self, font_id: usize, font_size: f32) -> GlyphMetricInfo {
+        let font_size = (font_size as f64 * self.scale).round() as u32;
+        font_metric(&self.fonts[font_id].font, font_size, self.scale)
     }
 
+    /// Where a glyph is in the atlas, rasterizing it if it isn't there yet.
     pub(crate) fn get_glyph_pos(
         &mut self,
-        c: char,
-        font_family: FontFamily,
+        font_id: usize,
+        glyph_id: u32,
         font_size: f32,
-        font_weight: FontWeight,
-        device: &wgpu::Device,
-        staging_belt: &mut wgpu::util::StagingBelt,
-        encoder: &mut wgpu::CommandEncoder,
+        upload: Option<GlyphUpload>,
     ) -> Result<&GlyphPosInfo, piet::Error> {
         let scale = self.scale;
 
         let font_size = (font_size as f64 * scale).round() as u32;
-        let glyph = self.get_glyph_info(c, font_family.clone(), font_weight, font_size)?;
+        let glyph = GlyphInfo {
+            font_id,
+            glyph_id,
+            font_size,
+        };
 
         if let Some((row, index)) = self.glyphs.get(&glyph) {
             let row = self.rows.get(row).unwrap();
@@ -642,29 +1007,12 @@ impl Cache {
         }
 
         let padding = 2.0;
-        let font = &self.fonts[glyph.font_id];
-        let font_metrics = font.metrics();
-        let units_per_em = font_metrics.units_per_em as f32;
-        let glyph_real_width =
-            font.advance(glyph.glyph_id).unwrap().x() / units_per_em * font_size as f32;
-        let glyph_real_height =
-            (font_metrics.ascent - font_metrics.descent + font_metrics.line_gap) / units_per_em
-                * font_size as f32;
-        let glyph_metric = GlyphMetricInfo {
-            ascent: (font_metrics.ascent / units_per_em * font_size as f32) as f64 / scale,
-            descent: (font_metrics.descent / units_per_em * font_size as f32) as f64 / scale,
-            line_gap: (font_metrics.line_gap / units_per_em * font_size as f32) as f64 / scale,
-            mono: font.is_monospace(),
-        };
-        let glyph_rect = Size::new(glyph_real_width as f64, glyph_real_height as f64).to_rect();
-
-        let glyph_width = glyph_real_width.ceil() as u32 + padding as u32;
-        let glyph_height = glyph_real_height.ceil() as u32 + padding as u32;
-
-        let mut canvas = Canvas::new(
-            Vector2I::new(glyph_width as i32, glyph_height as i32),
-            Format::A8,
-        );
+        let font = &self.fonts[glyph.font_id].font;
+        let units_per_em = font.metrics().units_per_em as f32;
+        let glyph_advance = font.advance(glyph.glyph_id).map(|a| a.x()).unwrap_or(0.0)
+            / units_per_em
+            * font_size as f32;
+        let glyph_metric = font_metric(font, font_size, scale);
 
         #[cfg(target_os = "macos")]
         let hinting_options = HintingOptions::None;
@@ -675,46 +1023,76 @@ impl Cache {
         #[cfg(target_os = "linux")]
         let hinting_options = HintingOptions::Full(font_size as f32);
 
-        font.rasterize_glyph(
-            &mut canvas,
-            glyph.glyph_id,
-            font_size as f32,
-            Transform2F::from_translation(Vector2F::new(
-                padding / 2.0,
-                font_metrics.ascent / units_per_em * font_size as f32 + padding / 2.0,
-            )),
-            hinting_options,
-            RasterizationOptions::GrayscaleAa,
-        )
-        .map_err(|_| piet::Error::MissingFont)?;
+        // The glyph's pixels, with its origin on the baseline.
+        let raster_bounds = font
+            .raster_bounds(
+                glyph.glyph_id,
+                font_size as f32,
+                Transform2F::default(),
+                hinting_options,
+                RasterizationOptions::GrayscaleAa,
+            )
+            .map_err(|_| piet::Error::MissingFont)?;
+        let glyph_rect = Rect::new(
+            raster_bounds.min_x() as f64,
+            raster_bounds.min_y() as f64,
+            raster_bounds.max_x() as f64,
+            raster_bounds.max_y() as f64,
+        ) + Vec2::new(0.0, glyph_metric.ascent * scale);
+
+        let glyph_width = raster_bounds.width() as u32 + padding as u32;
+        let glyph_height = raster_bounds.height() as u32 + padding as u32;
+        // Rows are shared by glyphs of about the same height.
+        let row_height = glyph_height.div_ceil(4) * 4;
+
+        let mut canvas = Canvas::new(
+            Vector2I::new(glyph_width as i32, glyph_height as i32),
+            Format::A8,
+        );
+
+        let transform = Transform2F::from_translation(
+            Vector2F::splat(padding / 2.0) - raster_bounds.origin().to_f32(),
+        );
+        // FreeType hands out a null bitmap for glyphs without any pixels,
+        // which font-kit can't blit from, so those aren't rasterized at all.
+        if raster_bounds.width() > 0 && raster_bounds.height() > 0 {
+            font.rasterize_glyph(
+                &mut canvas,
+                glyph.glyph_id,
+                font_size as f32,
+                transform,
+                hinting_options,
+                RasterizationOptions::GrayscaleAa,
+            )
+            .map_err(|_| piet::Error::MissingFont)?;
+        }
 
         let mut offset = [0, 0];
         let mut inserted = false;
         for (row_number, row) in self.rows.iter_mut().rev() {
-            if row.height == glyph_height {
-                if self.width - row.width > glyph_width {
-                    let origin = Point::new(
-                        row.width as f64 + padding as f64 / 2.0,
-                        row.y as f64 + padding as f64 / 2.0,
-                    );
-                    let glyph_pos = glyph_rect_to_pos(
-                        glyph_rect,
-                        origin,
-                        &glyph,
-                        &glyph_metric,
-                        scale,
-                        [self.width, self.height],
-                    );
-
-                    row.glyphs.push(glyph_pos);
-                    offset[0] = row.width;
-                    offset[1] = row.y;
-                    row.width += glyph_width;
-                    self.glyphs
-                        .insert(glyph.clone(), (*row_number, row.glyphs.len() - 1));
-                    inserted = true;
-                    break;
-                }
+            if row.height == row_height && self.width - row.width > glyph_width {
+                let origin = Point::new(
+                    row.width as f64 + padding as f64 / 2.0,
+                    row.y as f64 + padding as f64 / 2.0,
+                );
+                let glyph_pos = glyph_rect_to_pos(
+                    glyph_rect,
+                    origin,
+                    glyph_advance as f64,
+                    &glyph,
+                    &glyph_metric,
+                    scale,
+                    [self.width, self.height],
+                );
+
+                row.glyphs.push(glyph_pos);
+                offset[0] = row.width;
+                offset[1] = row.y;
+                row.width += glyph_width;
+                self.glyphs
+                    .insert(glyph.clone(), (*row_number, row.glyphs.len() - 1));
+                inserted = true;
+                break;
             }
         }
 
@@ -724,7 +1102,7 @@ impl Cache {
                 let last_row = self.rows.get(&(self.rows.len() - 1)).unwrap();
                 y = last_row.y + last_row.height;
             }
-            if self.height < y + glyph_height {
+            if self.height < y + row_height {
                 return Err(piet::Error::MissingFont);
             }
 
@@ -732,6 +1110,7 @@ impl Cache {
             let glyph_pos = glyph_rect_to_pos(
                 glyph_rect,
                 origin,
+                glyph_advance as f64,
                 &glyph,
                 &glyph_metric,
                 scale,
@@ -744,7 +1123,7 @@ impl Cache {
             let glyphs = vec![glyph_pos];
             let row = Row {
                 y,
-                height: glyph_height,
+                height: row_height,
                 width: glyph_width,
                 glyphs,
             };
@@ -753,21 +1132,14 @@ impl Cache {
             self.glyphs.insert(glyph.clone(), (new_row, 0));
         }
 
-        self.update(
-            device,
-            staging_belt,
-            encoder,
-            offset,
-            [glyph_width, glyph_height],
-            &canvas.pixels,
-        );
+        self.update(upload, offset, [glyph_width, glyph_height], &canvas.pixels);
 
         let (row, index) = self.glyphs.get(&glyph).unwrap();
         let row = self.rows.get(row).unwrap();
         Ok(&row.glyphs[*index])
     }
 
-    fn get_font_by_family(&mut self, family: FontFamily, weight: FontWeight) -> usize {
+    pub(crate) fn get_font_by_family(&mut self, family: FontFamily, weight: FontWeight) -> usize {
         if !self.font_families.contains_key(&(family.clone(), weight)) {
             let font = self.get_new_font(&family, weight);
             let font_id = self.fonts.len();
@@ -779,7 +1151,7 @@ impl Cache {
         *font_id
     }
 
-    fn get_new_font(&self, family: &FontFamily, weight: FontWeight) -> Font {
+    fn get_new_font(&self, family: &FontFamily, weight: FontWeight) -> CachedFont {
         let family_name = match family.inner() {
             piet::FontFamilyInner::Serif => FamilyName::Serif,
             piet::FontFamilyInner::SansSerif => FamilyName::SansSerif,
@@ -798,16 +1170,21 @@ impl Cache {
                     .weight(font_kit::properties::Weight(weight.to_raw() as f32)),
             )
             .ok()
-            .and_then(|h| h.load().ok())
+            .and_then(|h| {
+                let index = match &h {
+                    Handle::Path { font_index, .. } | Handle::Memory { font_index, .. } => {
+                        *font_index
+                    }
+                };
+                h.load().ok().map(|font| CachedFont::new(font, index))
+            })
             .unwrap_or(self.default_font.clone());
         font
     }
 
     pub fn update(
         &mut self,
-        device: &wgpu::Device,
-        staging_belt: &mut wgpu::util::StagingBelt,
-        encoder: &mut wgpu::CommandEncoder,
+        upload: Option<GlyphUpload>,
         offset: [u32; 2],
         size: [u32; 2],
         data: &[u8],
@@ -819,6 +1196,25 @@ impl Cache {
             return;
         }
 
+        let (upload, texture) = match (upload, self.texture.as_mut()) {
+            (Some(upload), Some(texture)) => (upload, texture),
+            (None, Some(_)) => return,
+            (_, None) => {
+                for row in 0..height {
+                    let start =
+                        (offset[1] as usize + row) * self.width as usize + offset[0] as usize;
+                    self.pixels[start..start + width]
+                        .copy_from_slice(&data[row * width..(row + 1) * width]);
+                }
+                return;
+            }
+        };
+        let GlyphUpload {
+            device,
+            staging_belt,
+            encoder,
+        } = upload;
+
         // It is a webgpu requirement that:
         //  BufferCopyView.layout.bytes_per_row % wgpu::COPY_BYTES_PER_ROW_ALIGNMENT == 0
         // So we calculate padded_width by rounding width
@@ -829,20 +1225,20 @@ impl Cache {
 
         let padded_data_size = (padded_width * height) as u64;
 
-        if self.upload_buffer_size < padded_data_size {
-            self.upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        if texture.upload_buffer_size < padded_data_size {
+            texture.upload_buffer = device.create_buffer(&wgpu::BufferDescriptor {
                 label: Some("wgpu_glyph::Cache upload buffer"),
                 size: padded_data_size,
                 usage: wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
                 mapped_at_creation: false,
             });
 
-            self.upload_buffer_size = padded_data_size;
+            texture.upload_buffer_size = padded_data_size;
         }
 
         let mut padded_data = staging_belt.write_buffer(
             encoder,
-            &self.upload_buffer,
+            &texture.upload_buffer,
             0,
             NonZeroU64::new(padded_data_size).unwrap(),
             device,
@@ -856,7 +1252,7 @@ impl Cache {
         // TODO: Move to use Queue for less buffer usage
         encoder.copy_buffer_to_texture(
             wgpu::ImageCopyBuffer {
-                buffer: &self.upload_buffer,
+                buffer: &texture.upload_buffer,
                 layout: wgpu::ImageDataLayout {
                     offset: 0,
                     bytes_per_row: NonZeroU32::new(padded_width as u32),
@@ -864,7 +1260,7 @@ impl Cache {
                 },
             },
             wgpu::ImageCopyTexture {
-                texture: &self.texture,
+                texture: &texture.texture,
                 mip_level: 0,
                 origin: wgpu::Origin3d {
                     x: u32::from(offset[0]),
@@ -882,29 +1278,44 @@ impl Cache {
     }
 }
 
+/// The metrics of `font` at `font_size` pixels, in logical units.
+fn font_metric(font: &Font, font_size: u32, scale: f64) -> GlyphMetricInfo {
+    let font_metrics = font.metrics();
+    let units = font_size as f64 / font_metrics.units_per_em as f64 / scale;
+    GlyphMetricInfo {
+        ascent: font_metrics.ascent as f64 * units,
+        descent: font_metrics.descent as f64 * units,
+        line_gap: font_metrics.line_gap as f64 * units,
+        mono: font.is_monospace(),
+    }
+}
+
+/// Place a glyph whose pixels are `glyph_rect`, relative to where it's drawn
+/// from at the top of the line, at `origin` in the atlas.
 fn glyph_rect_to_pos(
     glyph_rect: Rect,
     origin: Point,
+    advance: f64,
     glyph: &GlyphInfo,
     glyph_metric: &GlyphMetricInfo,
     scale: f64,
     size: [u32; 2],
 ) -> GlyphPosInfo {
-    let glyph_rect = glyph_rect.with_origin(origin);
-    let mut cache_rect = glyph_rect.clone();
+    let mut cache_rect = glyph_rect.with_origin(origin);
     cache_rect.x0 /= size[0] as f64;
     cache_rect.x1 /= size[0] as f64;
     cache_rect.y0 /= size[1] as f64;
     cache_rect.y1 /= size[1] as f64;
-    let glyph_pos = GlyphPosInfo {
+    GlyphPosInfo {
         info: glyph.clone(),
-        rect: glyph_rect.with_size(Size::new(
-            glyph_rect.size().width / scale,
-            glyph_rect.size().height / scale,
-        )),
-        width: glyph_rect.size().width / scale,
+        rect: Rect::new(
+            glyph_rect.x0 / scale,
+            glyph_rect.y0 / scale,
+            glyph_rect.x1 / scale,
+            glyph_rect.y1 / scale,
+        ),
+        width: advance / scale,
         metric: glyph_metric.clone(),
         cache_rect,
-    };
-    glyph_pos
+    }
 }
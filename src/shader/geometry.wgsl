@@ -1,12 +1,19 @@
 struct Primitive {
-    u_clip_rect: vec4<f32>;
     u_transform_1: vec4<f32>;
     u_blur_rect: vec4<f32>;
     u_transform_2: vec2<f32>;
     u_translate: vec2<f32>;
     u_scale: vec2<f32>;
-    u_clip: f32;
     u_blur_radius: f32;
+    u_paint: u32;
+    u_gradient: vec4<f32>;
+    u_stops_start: u32;
+    u_stops_count: u32;
+    u_extend: u32;
+    u_gradient_radius: f32;
+    u_image_transform_1: vec4<f32>;
+    u_image_bounds: vec4<f32>;
+    u_image_transform_2: vec2<f32>;
 };
 
 struct Globals {
@@ -18,10 +25,22 @@ struct Primitives {
     data: array<Primitive>;
 };
 
+struct GradientStop {
+    color: vec4<f32>;
+    pos: f32;
+};
+
+struct GradientStops {
+    data: array<GradientStop>;
+};
+
 [[group(0), binding(0)]] var<uniform> globals: Globals;
 [[group(0), binding(1)]] var font_sampler: sampler;
 [[group(0), binding(2)]] var font_tex: texture_2d<f32>;
 [[group(0), binding(3)]] var<storage> primitives: Primitives;
+[[group(0), binding(4)]] var<storage> gradient_stops: GradientStops;
+[[group(1), binding(0)]] var image_tex: texture_2d<f32>;
+[[group(1), binding(1)]] var image_sampler: sampler;
     
 struct VertexInput {
     [[location(0)]] v_pos: vec2<f32>;
@@ -40,8 +59,7 @@ struct VertexOutput {
     [[location(3)]] blur_radius: f32;
     [[location(4)]] tex: f32;
     [[location(5)]] tex_pos: vec2<f32>;
-    [[location(6)]] clip: f32;
-    [[location(7)]] clip_rect: vec4<f32>;
+    [[location(6), interpolate(flat)]] primitive_id: u32;
 };
 
 [[stage(vertex)]]
@@ -73,17 +91,7 @@ fn vs_main(input: VertexInput) -> VertexOutput {
     out.pos = input.v_pos;
     out.tex = input.v_tex;
     out.tex_pos = input.v_tex_pos;
-    out.clip = primitive.u_clip;
-    out.clip_rect = primitive.u_clip_rect;
-    
-    if (out.clip > 0.0) {
-        var left_top = vec2<f32>(primitive.u_clip_rect.x, primitive.u_clip_rect.y);
-        var left_top = left_top * globals.u_scale;
-        
-        var right_bottom = vec2<f32>(primitive.u_clip_rect.z, primitive.u_clip_rect.w);
-        var right_bottom = right_bottom * globals.u_scale;
-        out.clip_rect = vec4<f32>(left_top, right_bottom);
-    }
+    out.primitive_id = input.v_primitive_id;
     
     return out;
 }
@@ -102,9 +110,113 @@ fn box_shadow(lower: vec2<f32>, upper: vec2<f32>, point: vec2<f32>, radius: f32)
     return (integral.z - integral.x) * (integral.w - integral.y);
 }
 
+fn srgb_to_linear(c: vec3<f32>) -> vec3<f32> {
+    return select(pow((c + 0.055) / 1.055, vec3<f32>(2.4)), c / 12.92, c <= vec3<f32>(0.04045));
+}
+
+fn extend(t: f32, mode: u32) -> f32 {
+    if (mode == 1u) {
+        return fract(t);
+    }
+    if (mode == 2u) {
+        return 1.0 - abs(fract(t * 0.5) * 2.0 - 1.0);
+    }
+    return clamp(t, 0.0, 1.0);
+}
+
+fn linear_gradient_t(primitive: Primitive, pos: vec2<f32>) -> f32 {
+    let start = primitive.u_gradient.xy;
+    let direction = primitive.u_gradient.zw - start;
+    let length_squared = dot(direction, direction);
+    if (length_squared <= 0.0) {
+        return 0.0;
+    }
+    return dot(pos - start, direction) / length_squared;
+}
+
+// Where `pos` is on a radial gradient: the largest `t` for which it's on the
+// circle around `mix(focal, center, t)` with radius `t * radius`. Negative
+// where there's no such circle.
+fn radial_gradient_t(primitive: Primitive, pos: vec2<f32>) -> f32 {
+    let center = primitive.u_gradient.xy;
+    let focal = primitive.u_gradient.zw;
+    let radius = primitive.u_gradient_radius;
+    let cd = center - focal;
+    let pd = pos - focal;
+    let a = dot(cd, cd) - radius * radius;
+    let b = dot(pd, cd);
+    let c = dot(pd, pd);
+    if (a == 0.0) {
+        if (b <= 0.0) {
+            return -1.0;
+        }
+        return c / (2.0 * b);
+    }
+    let discriminant = b * b - a * c;
+    if (discriminant < 0.0) {
+        return -1.0;
+    }
+    let root = sqrt(discriminant);
+    return max((b + root) / a, (b - root) / a);
+}
+
+fn gradient_color(primitive: Primitive, offset: f32) -> vec4<f32> {
+    let t = extend(offset, primitive.u_extend);
+
+    let first = primitive.u_stops_start;
+    let last = first + primitive.u_stops_count - 1u;
+    var color: vec4<f32> = gradient_stops.data[first].color;
+    if (t >= gradient_stops.data[last].pos) {
+        color = gradient_stops.data[last].color;
+    }
+    for (var i: u32 = first; i < last; i = i + 1u) {
+        let a = gradient_stops.data[i];
+        let b = gradient_stops.data[i + 1u];
+        if (a.pos <= t && t < b.pos) {
+            color = mix(a.color, b.color, (t - a.pos) / (b.pos - a.pos));
+        }
+    }
+    return vec4<f32>(srgb_to_linear(color.rgb), color.a);
+}
+
 [[stage(fragment)]]
 fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let primitive = primitives.data[input.primitive_id];
+
+    // Sampled in uniform control flow, it's only used by image paints.
+    let image_transform = mat3x3<f32>(
+        vec3<f32>(primitive.u_image_transform_1.x, primitive.u_image_transform_1.y, 0.0),
+        vec3<f32>(primitive.u_image_transform_1.z, primitive.u_image_transform_1.w, 0.0),
+        vec3<f32>(primitive.u_image_transform_2.x, primitive.u_image_transform_2.y, 1.0),
+    );
+    let uv = (image_transform * vec3<f32>(input.pos.x, input.pos.y, 1.0)).xy;
+    let bounds = primitive.u_image_bounds;
+    let texel = textureSampleGrad(
+        image_tex,
+        image_sampler,
+        clamp(uv, bounds.xy, bounds.zw),
+        dpdx(uv),
+        dpdy(uv)
+    );
+
     var color: vec4<f32> = input.color;
+    if (primitive.u_paint == 1u) {
+        color = color * gradient_color(primitive, linear_gradient_t(primitive, input.pos));
+    } else if (primitive.u_paint == 2u) {
+        let t = radial_gradient_t(primitive, input.pos);
+        if (t < 0.0) {
+            color = vec4<f32>(0.0);
+        } else {
+            color = color * gradient_color(primitive, t);
+        }
+    } else if (primitive.u_paint == 3u) {
+        // Image texels are premultiplied.
+        if (texel.a > 0.0) {
+            color = color * vec4<f32>(texel.rgb / texel.a, texel.a);
+        } else {
+            color = vec4<f32>(0.0);
+        }
+    }
     
     if (input.blur_radius > 0.0) {
         if (input.rect.x <= input.pos.x && input.pos.x <= input.rect.z && input.rect.y <= input.pos.y && input.pos.y <= input.rect.w) {
@@ -127,11 +239,5 @@ fn fs_main(input: VertexOutput) -> [[location(0)]] vec4<f32> {
         color.w = color.w * alpha;
     }
     
-    if (input.clip > 0.0) {
-        if (input.position.x < input.clip_rect.x || input.position.x > input.clip_rect.z || input.position.y < input.clip_rect.y || input.position.y > input.clip_rect.w) {
-            discard;
-        }
-    }
-    
     return color;
 }
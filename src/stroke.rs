@@ -0,0 +1,248 @@
+use lyon::lyon_tessellation::StrokeOptions;
+use lyon::tessellation;
+use piet::kurbo::{
+    BezPath, CubicBez, Line, ParamCurve, ParamCurveArclen, PathEl, PathSeg, Point, QuadBez, Shape,
+};
+use piet::{LineCap, LineJoin, StrokeStyle};
+
+/// Tolerance used to turn shapes into path elements.
+const TOLERANCE: f64 = 0.01;
+
+/// Accuracy of arc length computations on curves.
+const ARCLEN_ACCURACY: f64 = 1e-3;
+
+/// The path and tessellator options to stroke `shape` with `width` and
+/// `style`.
+pub(crate) fn prepare(
+    shape: &impl Shape,
+    width: f64,
+    style: &StrokeStyle,
+) -> (BezPath, StrokeOptions) {
+    let mut path = if style.dash_pattern.is_empty() {
+        shape.path_elements(TOLERANCE).collect()
+    } else {
+        dash(shape, &style.dash_pattern, style.dash_offset)
+    };
+
+    let line_cap = match style.line_cap {
+        LineCap::Butt => tessellation::LineCap::Butt,
+        LineCap::Round => tessellation::LineCap::Round,
+        // lyon only pushes square caps one unit out instead of half the line
+        // width, so the open ends are extended here and cut off square.
+        LineCap::Square => {
+            path = extend_ends(&path, width / 2.0);
+            tessellation::LineCap::Butt
+        }
+    };
+    let options = StrokeOptions::tolerance(0.02)
+        .with_line_width(width as f32)
+        .with_line_cap(line_cap);
+    let options = match style.line_join {
+        // piet's limit is the ratio of the miter length to the line width,
+        // lyon compares that ratio against its limit divided by the square
+        // root of two.
+        LineJoin::Miter { limit } => options
+            .with_line_join(tessellation::LineJoin::Miter)
+            .with_miter_limit(
+                ((limit * std::f64::consts::SQRT_2) as f32).max(StrokeOptions::MINIMUM_MITER_LIMIT),
+            ),
+        LineJoin::Round => options.with_line_join(tessellation::LineJoin::Round),
+        LineJoin::Bevel => options.with_line_join(tessellation::LineJoin::Bevel),
+    };
+    (path, options)
+}
+
+/// Extend both ends of every open subpath of `path` by `distance`, along
+/// the tangents at the ends.
+fn extend_ends(path: &BezPath, distance: f64) -> BezPath {
+    let mut extended = BezPath::new();
+    let mut subpath = Vec::new();
+    for el in path.elements() {
+        if let PathEl::MoveTo(_) = el {
+            extend_subpath(&subpath, distance, &mut extended);
+            subpath.clear();
+        }
+        subpath.push(*el);
+    }
+    extend_subpath(&subpath, distance, &mut extended);
+    extended
+}
+
+fn extend_subpath(subpath: &[PathEl], distance: f64, out: &mut BezPath) {
+    let points: Vec<Point> = subpath
+        .iter()
+        .flat_map(|el| match *el {
+            PathEl::MoveTo(p) | PathEl::LineTo(p) => vec![p],
+            PathEl::QuadTo(p1, p2) => vec![p1, p2],
+            PathEl::CurveTo(p1, p2, p3) => vec![p1, p2, p3],
+            PathEl::ClosePath => vec![],
+        })
+        .collect();
+    let closed = matches!(subpath.last(), Some(PathEl::ClosePath));
+    let (start, end) = match (points.first(), points.last()) {
+        (Some(start), Some(end)) if !closed => (*start, *end),
+        _ => {
+            out.extend(subpath.iter().cloned());
+            return;
+        }
+    };
+    let start_tangent = points.iter().find(|p| **p != start).map(|p| start - *p);
+    let end_tangent = points.iter().rev().find(|p| **p != end).map(|p| end - *p);
+    let (start_tangent, end_tangent) = match (start_tangent, end_tangent) {
+        (Some(start_tangent), Some(end_tangent)) => (start_tangent, end_tangent),
+        // A subpath without any length has no direction to extend in.
+        _ => {
+            out.extend(subpath.iter().cloned());
+            return;
+        }
+    };
+
+    out.move_to(start + start_tangent.normalize() * distance);
+    out.line_to(start);
+    out.extend(subpath[1..].iter().cloned());
+    out.line_to(end + end_tangent.normalize() * distance);
+}
+
+/// Cut `shape` into the dashes of `pattern`, starting `offset` into it.
+///
+/// The pattern restarts for every subpath. A dash running over the start of
+/// a closed subpath is joined with the one it continues into. Patterns that
+/// can't be drawn (negative or non-finite lengths, or all zeros) give back
+/// the whole shape.
+fn dash(shape: &impl Shape, pattern: &[f64], offset: f64) -> BezPath {
+    let mut pattern = pattern.to_vec();
+    if pattern.len() % 2 == 1 {
+        pattern.extend_from_within(..);
+    }
+    let total: f64 = pattern.iter().sum();
+    if pattern.iter().any(|len| !len.is_finite() || *len < 0.0) || total <= 0.0 {
+        return shape.path_elements(TOLERANCE).collect();
+    }
+
+    let dasher = Dasher {
+        pattern: &pattern,
+        offset: offset.rem_euclid(total),
+    };
+    let mut dashes = BezPath::new();
+    let mut segments = Vec::new();
+    let mut start = Point::ZERO;
+    let mut last = Point::ZERO;
+    for el in shape.path_elements(TOLERANCE) {
+        match el {
+            PathEl::MoveTo(p) => {
+                dasher.dash_subpath(&segments, false, &mut dashes);
+                segments.clear();
+                start = p;
+                last = p;
+            }
+            PathEl::LineTo(p) => {
+                segments.push(PathSeg::Line(Line::new(last, p)));
+                last = p;
+            }
+            PathEl::QuadTo(p1, p2) => {
+                segments.push(PathSeg::Quad(QuadBez::new(last, p1, p2)));
+                last = p2;
+            }
+            PathEl::CurveTo(p1, p2, p3) => {
+                segments.push(PathSeg::Cubic(CubicBez::new(last, p1, p2, p3)));
+                last = p3;
+            }
+            PathEl::ClosePath => {
+                if last != start {
+                    segments.push(PathSeg::Line(Line::new(last, start)));
+                }
+                dasher.dash_subpath(&segments, true, &mut dashes);
+                segments.clear();
+                last = start;
+            }
+        }
+    }
+    dasher.dash_subpath(&segments, false, &mut dashes);
+    dashes
+}
+
+struct Dasher<'a> {
+    /// Alternating dash and gap lengths, of even length.
+    pattern: &'a [f64],
+    /// Where in the pattern each subpath starts, less than its total length.
+    offset: f64,
+}
+
+impl<'a> Dasher<'a> {
+    fn dash_subpath(&self, segments: &[PathSeg], closed: bool, out: &mut BezPath) {
+        if segments.is_empty() {
+            return;
+        }
+
+        let mut index = 0;
+        let mut phase = self.offset;
+        while phase >= self.pattern[index] {
+            phase -= self.pattern[index];
+            index = (index + 1) % self.pattern.len();
+        }
+        let mut remaining = self.pattern[index] - phase;
+        let starts_on = index % 2 == 0;
+        let mut on = starts_on;
+
+        let mut dashes = Vec::new();
+        let mut current = BezPath::new();
+        for seg in segments {
+            let len = seg.arclen(ARCLEN_ACCURACY);
+            let mut done = 0.0;
+            while len - done > remaining {
+                let end = done + remaining;
+                if on {
+                    append(&mut current, seg, done, end);
+                    dashes.push(std::mem::take(&mut current));
+                }
+                done = end;
+                index = (index + 1) % self.pattern.len();
+                remaining = self.pattern[index];
+                on = !on;
+            }
+            if on {
+                append(&mut current, seg, done, len);
+            }
+            remaining -= len - done;
+        }
+
+        if on && !current.elements().is_empty() {
+            if dashes.is_empty() {
+                // The whole subpath is a single dash.
+                if closed {
+                    current.close_path();
+                }
+                dashes.push(current);
+            } else if closed && starts_on {
+                let first = dashes[0].elements().iter().skip(1);
+                current.extend(first.cloned());
+                dashes[0] = current;
+            } else {
+                dashes.push(current);
+            }
+        }
+
+        for dash in dashes {
+            out.extend(dash);
+        }
+    }
+}
+
+/// Append the part of `seg` between arc lengths `from` and `to` to `path`.
+fn append(path: &mut BezPath, seg: &PathSeg, from: f64, to: f64) {
+    let t0 = if from <= 0.0 {
+        0.0
+    } else {
+        seg.inv_arclen(from, ARCLEN_ACCURACY)
+    };
+    let t1 = seg.inv_arclen(to, ARCLEN_ACCURACY);
+    let piece = seg.subsegment(t0..t1);
+    if path.elements().is_empty() {
+        path.move_to(piece.start());
+    }
+    match piece {
+        PathSeg::Line(line) => path.line_to(line.p1),
+        PathSeg::Quad(quad) => path.quad_to(quad.p1, quad.p2),
+        PathSeg::Cubic(cubic) => path.curve_to(cubic.p1, cubic.p2, cubic.p3),
+    }
+}
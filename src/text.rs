@@ -5,43 +5,50 @@ use lyon::lyon_tessellation::{
     BuffersBuilder, FillOptions, FillTessellator, FillVertex, StrokeOptions, StrokeTessellator,
     StrokeVertex, VertexBuffers,
 };
-use piet::kurbo::Line;
+use piet::kurbo::{Line, Vec2};
 use piet::Color;
 use piet::{
     kurbo::{Point, Size},
     FontFamily, FontStyle, FontWeight, HitTestPoint, HitTestPosition, LineMetric, Text,
-    TextAttribute, TextLayout, TextLayoutBuilder, TextStorage,
+    TextAlignment, TextAttribute, TextLayout, TextLayoutBuilder, TextStorage,
 };
+use rustybuzz::ttf_parser::Tag;
+use unicode_bidi::{BidiClass, BidiInfo, Level};
+use unicode_linebreak::{linebreaks, BreakOpportunity};
+use unicode_script::{Script, UnicodeScript};
 use unicode_width::UnicodeWidthChar;
 
 use crate::context::{format_color, from_linear, WgpuRenderContext};
-use crate::pipeline::{Cache, GlyphMetricInfo, GlyphPosInfo, GpuVertex};
+use crate::pipeline::{Cache, GlyphMetricInfo, GlyphPosInfo, GlyphUpload, GpuVertex, ShapedGlyph};
 
 #[derive(Clone)]
 pub struct WgpuText {
     source: Rc<RefCell<SystemSource>>,
     glyphs: Rc<RefCell<HashMap<FontFamily, HashMap<char, Rc<(Vec<[f32; 2]>, Vec<u32>)>>>>>,
     pub(crate) cache: Rc<RefCell<Cache>>,
-    device: Rc<wgpu::Device>,
-    staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
-    encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+    upload: Option<TextUpload>,
     fill_tess: Rc<RefCell<FillTessellator>>,
     stroke_tess: Rc<RefCell<StrokeTessellator>>,
 }
 
+/// The handles used to copy new glyphs into the atlas texture.
+///
+/// Renderers without a device rasterize on the CPU and don't have any.
+#[derive(Clone)]
+pub(crate) struct TextUpload {
+    pub(crate) device: Rc<wgpu::Device>,
+    pub(crate) staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
+    pub(crate) encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
+}
+
 impl WgpuText {
-    pub(crate) fn new(
-        device: Rc<wgpu::Device>,
-        staging_belt: Rc<RefCell<wgpu::util::StagingBelt>>,
-        encoder: Rc<RefCell<Option<wgpu::CommandEncoder>>>,
-    ) -> Self {
+    pub(crate) fn new(upload: Option<TextUpload>) -> Self {
+        let device = upload.as_ref().map(|upload| &*upload.device);
         Self {
             source: Rc::new(RefCell::new(SystemSource::new())),
             glyphs: Rc::new(RefCell::new(HashMap::new())),
-            cache: Rc::new(RefCell::new(Cache::new(&device, 2000, 2000))),
-            device,
-            staging_belt,
-            encoder,
+            cache: Rc::new(RefCell::new(Cache::new(device, 2000, 2000))),
+            upload,
             fill_tess: Rc::new(RefCell::new(FillTessellator::new())),
             stroke_tess: Rc::new(RefCell::new(StrokeTessellator::new())),
         }
@@ -49,33 +56,41 @@ impl WgpuText {
 
     pub(crate) fn get_glyph_pos(
         &self,
-        c: char,
-        font_family: FontFamily,
+        font_id: usize,
+        glyph_id: u32,
         font_size: f32,
-        font_weight: FontWeight,
     ) -> Result<GlyphPosInfo, piet::Error> {
-        let mut encoder = self.encoder.borrow_mut();
+        let mut cache = self.cache.borrow_mut();
+        let upload = match &self.upload {
+            Some(upload) => upload,
+            None => {
+                return cache
+                    .get_glyph_pos(font_id, glyph_id, font_size, None)
+                    .cloned()
+            }
+        };
+
+        let mut encoder = upload.encoder.borrow_mut();
         if encoder.is_none() {
-            *encoder = Some(
-                self.device
-                    .create_command_encoder(&wgpu::CommandEncoderDescriptor {
-                        label: Some("render"),
-                    }),
-            );
+            *encoder = Some(upload.device.create_command_encoder(
+                &wgpu::CommandEncoderDescriptor {
+                    label: Some("render"),
+                },
+            ));
         }
 
-        let mut cache = self.cache.borrow_mut();
         cache
             .get_glyph_pos(
-                c,
-                font_family,
+                font_id,
+                glyph_id,
                 font_size,
-                font_weight,
-                &self.device,
-                &mut self.staging_belt.borrow_mut(),
-                encoder.as_mut().unwrap(),
+                Some(GlyphUpload {
+                    device: &upload.device,
+                    staging_belt: &mut upload.staging_belt.borrow_mut(),
+                    encoder: encoder.as_mut().unwrap(),
+                }),
             )
-            .map(|p| p.clone())
+            .cloned()
     }
 }
 
@@ -84,12 +99,83 @@ pub struct WgpuTextLayout {
     state: WgpuText,
     text: String,
     width: f64,
+    alignment: TextAlignment,
     attrs: Rc<Attributes>,
     ref_glyph: Rc<RefCell<GlyphPosInfo>>,
-    glyphs: Rc<RefCell<Vec<GlyphPosInfo>>>,
+    clusters: Rc<RefCell<Vec<LayoutCluster>>>,
+    lines: Rc<RefCell<Vec<LineMetric>>>,
     geometry: Rc<RefCell<VertexBuffers<GpuVertex, u32>>>,
 }
 
+/// A run of text shaped with one font at one size, in one direction.
+struct ShapedRun {
+    font_id: usize,
+    font_size: f32,
+    /// The distance from the top of a line of the font to its baseline.
+    ascent: f64,
+    /// The height of a line of the font.
+    height: f64,
+    /// The clusters of the run in logical order.
+    clusters: Vec<ShapedCluster>,
+}
+
+/// Glyphs that are shaped from the same characters and are laid out as a
+/// unit, like a ligature.
+struct ShapedCluster {
+    text: Range<usize>,
+    /// The glyphs in visual order.
+    glyphs: Vec<ShapedGlyph>,
+    advance: f64,
+}
+
+/// Where a cluster ended up in a layout.
+#[derive(Clone, Debug)]
+struct LayoutCluster {
+    text: Range<usize>,
+    /// The left edge of the cluster.
+    x: f64,
+    /// The top of the cluster's line.
+    y: f64,
+    width: f64,
+    height: f64,
+    rtl: bool,
+    /// Whether the cluster is whitespace hanging off the end of its line.
+    hanging: bool,
+    line: usize,
+}
+
+impl LayoutCluster {
+    /// The x of the caret before the character at `index`, which is in the
+    /// cluster or at its end. Carets inside a cluster split it evenly
+    /// between its characters.
+    fn caret_x(&self, text: &str, index: usize) -> f64 {
+        let count = text[self.text.clone()].chars().count().max(1);
+        let before = text[self.text.start..index].chars().count();
+        let offset = self.width * before as f64 / count as f64;
+        if self.rtl {
+            self.x + self.width - offset
+        } else {
+            self.x + offset
+        }
+    }
+
+    /// The character boundary in the cluster nearest to `x`.
+    fn hit_test(&self, text: &str, x: f64) -> usize {
+        let mut best = (self.text.start, f64::MAX);
+        let boundaries = text[self.text.clone()]
+            .char_indices()
+            .map(|(i, _)| self.text.start + i)
+            .chain(std::iter::once(self.text.end));
+        for index in boundaries {
+            let distance = (self.caret_x(text, index) - x).abs();
+            if distance < best.1 {
+                best = (index, distance);
+            }
+        }
+        best.0
+    }
+}
+
 impl WgpuTextLayout {
     pub fn new(text: String, state: WgpuText) -> Self {
         let char_number = text.chars().count();
@@ -99,8 +185,10 @@ impl WgpuTextLayout {
             state,
             text,
             width: f64::MAX,
+            alignment: TextAlignment::Start,
             attrs: Rc::new(Attributes::default()),
-            glyphs: Rc::new(RefCell::new(Vec::new())),
+            clusters: Rc::new(RefCell::new(Vec::new())),
+            lines: Rc::new(RefCell::new(Vec::new())),
             ref_glyph: Rc::new(RefCell::new(GlyphPosInfo::default())),
             geometry: Rc::new(RefCell::new(VertexBuffers::with_capacity(
                 num_vertices,
@@ -113,6 +201,10 @@ impl WgpuTextLayout {
         self.width = width;
     }
 
+    fn set_alignment(&mut self, alignment: TextAlignment) {
+        self.alignment = alignment;
+    }
+
     fn set_attrs(&mut self, attrs: Attributes) {
         self.attrs = Rc::new(attrs);
     }
@@ -124,106 +216,409 @@ impl WgpuTextLayout {
         }
     }
 
+    /// Split the text into runs of one font, size, script and direction, and
+    /// shape them.
+    fn shape(&self, bidi: &BidiInfo) -> Vec<ShapedRun> {
+        struct Item {
+            text: Range<usize>,
+            /// The font of the text's attributes, which `font_id` falls back
+            /// from if it doesn't have the glyphs.
+            family_font_id: usize,
+            font_id: usize,
+            font_size: f32,
+            script: Option<Script>,
+            level: Level,
+        }
+
+        let mut cache = self.state.cache.borrow_mut();
+        let mut items: Vec<Item> = Vec::new();
+        for (index, c) in self.text.char_indices() {
+            let family_font_id =
+                cache.get_font_by_family(self.attrs.font(index), self.attrs.font_weight(index));
+            let font_size = self.attrs.size(index) as f32;
+            let level = bidi.levels[index];
+            let script = c.script();
+            // Spaces, punctuation and combining marks go with what's around
+            // them.
+            let weak = matches!(script, Script::Common | Script::Inherited | Script::Unknown);
+
+            if let Some(item) = items.last_mut() {
+                if item.family_font_id == family_font_id
+                    && item.font_size == font_size
+                    && item.level == level
+                    && weak
+                    && (c.is_control() || cache.has_glyph(item.font_id, c))
+                {
+                    item.text.end = index + c.len_utf8();
+                    continue;
+                }
+            }
+
+            let font_id = if cache.has_glyph(family_font_id, c) {
+                family_font_id
+            } else {
+                cache.get_fallback_font(c).unwrap_or(family_font_id)
+            };
+            if let Some(item) = items.last_mut() {
+                if item.family_font_id == family_font_id
+                    && item.font_id == font_id
+                    && item.font_size == font_size
+                    && item.level == level
+                    && (weak || item.script.is_none() || item.script == Some(script))
+                {
+                    if !weak {
+                        item.script = Some(script);
+                    }
+                    item.text.end = index + c.len_utf8();
+                    continue;
+                }
+            }
+            items.push(Item {
+                text: index..index + c.len_utf8(),
+                family_font_id,
+                font_id,
+                font_size,
+                script: if weak { None } else { Some(script) },
+                level,
+            });
+        }
+
+        items
+            .into_iter()
+            .map(|item| {
+                let script = item.script.and_then(|script| {
+                    rustybuzz::Script::from_iso15924_tag(Tag::from_bytes_lossy(
+                        script.short_name().as_bytes(),
+                    ))
+                });
+                let rtl = item.level.is_rtl();
+                let glyphs = cache.shape(
+                    item.font_id,
+                    item.font_size,
+                    &self.text[item.text.clone()],
+                    script,
+                    rtl,
+                );
+
+                let mut clusters: Vec<ShapedCluster> = Vec::new();
+                for glyph in glyphs {
+                    let start = item.text.start + glyph.cluster;
+                    match clusters.last_mut() {
+                        Some(cluster) if cluster.text.start == start => {
+                            cluster.advance += glyph.x_advance;
+                            cluster.glyphs.push(glyph);
+                        }
+                        _ => clusters.push(ShapedCluster {
+                            text: start..start,
+                            advance: glyph.x_advance,
+                            glyphs: vec![glyph],
+                        }),
+                    }
+                }
+                if rtl {
+                    clusters.reverse();
+                }
+                let mut end = item.text.end;
+                for cluster in clusters.iter_mut().rev() {
+                    cluster.text.end = end;
+                    end = cluster.text.start;
+                }
+
+                let metric = cache.get_font_metric(item.font_id, item.font_size);
+                ShapedRun {
+                    font_id: item.font_id,
+                    font_size: item.font_size,
+                    ascent: metric.ascent,
+                    height: metric.ascent - metric.descent + metric.line_gap,
+                    clusters,
+                }
+            })
+            .collect()
+    }
+
     pub(crate) fn rebuild(&self, is_mono: bool, tab_width: usize, bounds: Option<[f64; 2]>) {
         let font_family = self.attrs.defaults.font.clone();
-        let font_size = self.attrs.defaults.font_size;
+        let font_size = self.attrs.defaults.font_size as f32;
         let font_weight = self.attrs.defaults.weight;
-        if let Ok(glyph_pos) =
-            self.state
-                .get_glyph_pos('W', font_family.clone(), font_size as f32, font_weight)
+        let ref_glyph = {
+            let mut cache = self.state.cache.borrow_mut();
+            let font_id = cache.get_font_by_family(font_family, font_weight);
+            let glyphs = cache.shape(font_id, font_size, "W", None, false);
+            (font_id, glyphs[0].glyph_id)
+        };
+        if let Ok(glyph_pos) = self
+            .state
+            .get_glyph_pos(ref_glyph.0, ref_glyph.1, font_size)
         {
             *self.ref_glyph.borrow_mut() = glyph_pos.clone();
         }
 
-        let mono_width = self.ref_glyph.borrow().rect.width();
+        let mono_width = self.ref_glyph.borrow().width;
 
-        let len = self.text.chars().count();
+        let bidi = BidiInfo::new(&self.text, None);
+        let runs = self.shape(&bidi);
 
-        let mut glyphs = self.glyphs.borrow_mut();
-        glyphs.clear();
-        glyphs.reserve(len);
-        let mut geometry = self.geometry.borrow_mut();
-        geometry.vertices.clear();
-        geometry.indices.clear();
-        geometry.vertices.reserve(4 * len);
-        geometry.indices.reserve(6 * len);
+        // Where lines may or must be broken, by byte offset.
+        let mut breaks = vec![None; self.text.len() + 1];
+        for (offset, opportunity) in linebreaks(&self.text) {
+            breaks[offset] = Some(opportunity);
+        }
 
+        // The clusters in logical order with their runs and widths, broken
+        // into lines at the last opportunity before they'd overflow. Words
+        // too long for a line of their own are broken between clusters.
+        let mut logical: Vec<(&ShapedRun, &ShapedCluster, f64)> = Vec::new();
+        let mut lines: Vec<Range<usize>> = Vec::new();
+        let mut line_start = 0;
+        let mut last_break = None;
         let mut x = 0.0;
-        let mut y = 0.0;
-        let mut max_height = 0.0;
-        let mut index = 0;
         let mut mono_char_widths = 0;
-        for c in self.text.chars() {
-            let font_family = self.attrs.font(index);
-            let font_size = self.attrs.size(index) as f32;
-            let font_weight = self.attrs.font_weight(index);
-            let color = self.attrs.color(index);
-            index += c.len_utf8();
-
-            let default_width = if is_mono {
-                let char_width = if c == '\t' {
-                    tab_width - mono_char_widths % tab_width
+        for run in &runs {
+            for cluster in &run.clusters {
+                let text = &self.text[cluster.text.clone()];
+                let width = if text.chars().all(is_line_separator) {
+                    0.0
+                } else if is_mono {
+                    let mut char_widths = 0;
+                    for c in text.chars() {
+                        char_widths += if c == '\t' {
+                            tab_width - (mono_char_widths + char_widths) % tab_width
+                        } else {
+                            UnicodeWidthChar::width(c).unwrap_or(1)
+                        };
+                    }
+                    mono_char_widths += char_widths;
+                    char_widths as f64 * mono_width
+                } else if text == "\t" {
+                    tab_width as f64 * mono_width
                 } else {
-                    UnicodeWidthChar::width(c).unwrap_or(1)
+                    cluster.advance
                 };
-                mono_char_widths += char_width;
-                let width = char_width as f32 * mono_width as f32;
-                width
-            } else {
-                let char_width = if c == '\t' {
-                    tab_width
-                } else {
-                    UnicodeWidthChar::width(c).unwrap_or(1)
-                };
-                let width = char_width as f32 * mono_width as f32;
-                width
-            };
-
-            let color = format_color(&color);
-            let mut glyph_pos = self
-                .state
-                .get_glyph_pos(c, font_family, font_size, font_weight)
-                .map(|g| g.clone())
-                .unwrap_or_else(|_| GlyphPosInfo::empty(default_width as f64));
-            let width = if is_mono {
-                glyph_pos.width = default_width as f64;
-                default_width
-            } else {
-                glyph_pos.rect.width() as f32
-            };
 
-            if (x + width) as f64 > self.width {
-                x = 0.0;
-                y += max_height;
+                let index = logical.len();
+                if index > line_start
+                    && breaks[cluster.text.start] == Some(BreakOpportunity::Allowed)
+                {
+                    last_break = Some(index);
+                }
+                // Whitespace hangs off the end of the line instead.
+                let whitespace = text.chars().all(char::is_whitespace);
+                if !whitespace && x + width > self.width && index > line_start {
+                    let at = last_break.unwrap_or(index);
+                    lines.push(line_start..at);
+                    line_start = at;
+                    last_break = None;
+                    x = logical[at..].iter().map(|(_, _, width)| width).sum();
+                }
+                x += width;
+                logical.push((run, cluster, width));
+
+                if cluster.text.end < self.text.len()
+                    && breaks[cluster.text.end] == Some(BreakOpportunity::Mandatory)
+                {
+                    lines.push(line_start..logical.len());
+                    line_start = logical.len();
+                    last_break = None;
+                    x = 0.0;
+                    mono_char_widths = 0;
+                }
             }
+        }
+        if logical.len() > line_start {
+            lines.push(line_start..logical.len());
+        }
 
-            glyph_pos.rect = glyph_pos.rect.with_origin((x as f64, y as f64));
+        let len = logical.len();
+        let mut clusters = self.clusters.borrow_mut();
+        clusters.clear();
+        clusters.reserve(len + 1);
+        let mut line_metrics = self.lines.borrow_mut();
+        line_metrics.clear();
+        line_metrics.reserve(lines.len() + 1);
+        let mut geometry = self.geometry.borrow_mut();
+        geometry.vertices.clear();
+        geometry.indices.clear();
+        geometry.vertices.reserve(4 * len);
+        geometry.indices.reserve(6 * len);
 
-            let new_x = x + width;
+        // The width of each line without the whitespace hanging off its end,
+        // which alignment leaves out.
+        let is_whitespace = |cluster: &ShapedCluster| {
+            self.text[cluster.text.clone()]
+                .chars()
+                .all(char::is_whitespace)
+        };
+        let hanging = |line: &[(&ShapedRun, &ShapedCluster, f64)]| {
+            line.iter()
+                .rev()
+                .take_while(|(_, cluster, _)| is_whitespace(cluster))
+                .count()
+        };
+        let content_widths: Vec<f64> = lines
+            .iter()
+            .map(|line| {
+                let line = &logical[line.clone()];
+                line[..line.len() - hanging(line)]
+                    .iter()
+                    .map(|(_, _, width)| width)
+                    .sum()
+            })
+            .collect();
+        // Without a max width, lines are aligned within the widest one.
+        let align_width = if self.width < f64::MAX {
+            self.width
+        } else {
+            content_widths.iter().cloned().fold(0.0, f64::max)
+        };
 
-            let height = glyph_pos.rect.height() as f32;
-            if height > max_height {
-                max_height = height;
+        // Lay each line out in visual order.
+        let mut y = 0.0;
+        for (line_number, line) in lines.into_iter().enumerate() {
+            let line = &logical[line];
+            let text = line[0].1.text.start..line[line.len() - 1].1.text.end;
+            let rtl = paragraph_level(&bidi, text.start).is_rtl();
+            let content_width = content_widths[line_number];
+            let hanging = hanging(line);
+            let hanging_width: f64 = line[line.len() - hanging..]
+                .iter()
+                .map(|(_, _, width)| width)
+                .sum();
+
+            // Justified lines spread what's left of the line between the
+            // spaces inside it, except the last line of a paragraph.
+            let mut spacing = 0.0;
+            let ends_paragraph = text.end == self.text.len()
+                || breaks[text.end] == Some(BreakOpportunity::Mandatory);
+            if self.alignment == TextAlignment::Justified && !ends_paragraph {
+                let spaces = line[..line.len() - hanging]
+                    .iter()
+                    .filter(|(_, cluster, _)| is_whitespace(cluster))
+                    .count();
+                if spaces > 0 && content_width < align_width {
+                    spacing = (align_width - content_width) / spaces as f64;
+                }
             }
+            let justified_width = if spacing > 0.0 {
+                align_width
+            } else {
+                content_width
+            };
 
-            if let Some(bounds) = bounds.as_ref() {
-                if x > bounds[1] as f32 {
-                    return;
+            let levels = line_levels(&bidi, text.clone());
+            let cluster_levels: Vec<Level> = line
+                .iter()
+                .map(|(_, cluster, _)| levels[cluster.text.start - text.start])
+                .collect();
+            // The runs on the line share the baseline of the tallest.
+            let baseline = line
+                .iter()
+                .map(|(run, _, _)| run.ascent)
+                .fold(0.0, f64::max);
+            let height = line
+                .iter()
+                .map(|(run, _, _)| baseline - run.ascent + run.height)
+                .fold(0.0, f64::max);
+
+            // Hanging whitespace is at the end of the line in the direction
+            // of the paragraph, past the aligned content.
+            let mut x = line_offset(self.alignment, rtl, align_width, justified_width);
+            if rtl {
+                x -= hanging_width;
+            }
+            for i in BidiInfo::reorder_visual(&cluster_levels) {
+                let (run, cluster, mut width) = line[i];
+                if i < line.len() - hanging && is_whitespace(cluster) {
+                    width += spacing;
                 }
-                if new_x < bounds[0] as f32 {
-                    x = new_x;
-                    glyphs.push(glyph_pos);
-                    continue;
+                let new_x = x + width;
+                let visible = match bounds {
+                    Some(bounds) => new_x >= bounds[0] && x <= bounds[1],
+                    None => true,
+                };
+                if visible && !is_whitespace(cluster) {
+                    self.add_glyphs(&mut geometry, run, cluster, x, y + baseline - run.ascent);
                 }
-            }
 
-            if c == ' ' || c == '\n' || c == '\t' {
+                clusters.push(LayoutCluster {
+                    text: cluster.text.clone(),
+                    x,
+                    y,
+                    width,
+                    height,
+                    rtl: cluster_levels[i].is_rtl(),
+                    hanging: i >= line.len() - hanging,
+                    line: line_number,
+                });
                 x = new_x;
-                glyphs.push(glyph_pos);
+            }
+            line_metrics.push(LineMetric {
+                start_offset: text.start,
+                end_offset: text.end,
+                trailing_whitespace: line[line.len() - hanging..]
+                    .iter()
+                    .map(|(_, cluster, _)| cluster.text.len())
+                    .sum(),
+                baseline,
+                height,
+                y_offset: y,
+            });
+            y += height;
+        }
+
+        // Empty text, or text ending in a line break, ends with an empty line
+        // for the caret in the default font, which an empty cluster holds.
+        if self.text.is_empty() || self.text.ends_with(is_line_separator) {
+            let rtl = !self.text.is_empty() && paragraph_level(&bidi, self.text.len() - 1).is_rtl();
+            let metric = &self.ref_glyph.borrow().metric;
+            let height = metric.ascent - metric.descent + metric.line_gap;
+            clusters.push(LayoutCluster {
+                text: self.text.len()..self.text.len(),
+                x: line_offset(self.alignment, rtl, align_width, 0.0),
+                y,
+                width: 0.0,
+                height,
+                rtl,
+                hanging: false,
+                line: line_metrics.len(),
+            });
+            line_metrics.push(LineMetric {
+                start_offset: self.text.len(),
+                end_offset: self.text.len(),
+                trailing_whitespace: 0,
+                baseline: metric.ascent,
+                height,
+                y_offset: y,
+            });
+        }
+    }
+
+    /// Add the quads of a cluster's glyphs at `x` on a line of the run's font
+    /// at `y`.
+    fn add_glyphs(
+        &self,
+        geometry: &mut VertexBuffers<GpuVertex, u32>,
+        run: &ShapedRun,
+        cluster: &ShapedCluster,
+        x: f64,
+        y: f64,
+    ) {
+        let color = format_color(self.attrs.color(cluster.text.start));
+        let mut pen = x;
+        for glyph in &cluster.glyphs {
+            let glyph_pos =
+                match self
+                    .state
+                    .get_glyph_pos(run.font_id, glyph.glyph_id, run.font_size)
+                {
+                    Ok(glyph_pos) => glyph_pos,
+                    Err(_) => continue,
+                };
+            let rect = glyph_pos.rect + Vec2::new(pen + glyph.x_offset, y - glyph.y_offset);
+            pen += glyph.x_advance;
+            if rect.area() == 0.0 {
                 continue;
             }
 
-            let rect = &glyph_pos.rect;
             let cache_rect = &glyph_pos.cache_rect;
             let mut vertices = vec![
                 GpuVertex {
@@ -267,13 +662,67 @@ impl WgpuTextLayout {
 
             geometry.vertices.append(&mut vertices);
             geometry.indices.append(&mut indices);
+        }
+    }
+
+    /// The carets at `idx`. The first is the one
+    /// [`hit_test_text_position`](TextLayout::hit_test_text_position)
+    /// returns, at the leading edge of the character at `idx`. Where `idx` is
+    /// between left-to-right and right-to-left text, the trailing edge of the
+    /// character before it is somewhere else on the line, and the second
+    /// caret is there.
+    pub fn hit_test_text_position_split(
+        &self,
+        idx: usize,
+    ) -> (HitTestPosition, Option<HitTestPosition>) {
+        let clusters = self.clusters.borrow();
+        let lines = self.lines.borrow();
+        let position = |cluster: &LayoutCluster| {
+            let line = &lines[cluster.line];
+            let mut pos = HitTestPosition::default();
+            pos.point = Point::new(
+                cluster.caret_x(&self.text, idx.min(cluster.text.end)),
+                line.y_offset + line.baseline,
+            );
+            pos.line = cluster.line;
+            pos
+        };
 
-            x = new_x;
-            glyphs.push(glyph_pos);
+        let leading = clusters.iter().find(|cluster| {
+            cluster.text.contains(&idx) || cluster.text.is_empty() && cluster.text.start == idx
+        });
+        let trailing = clusters
+            .iter()
+            .find(|cluster| cluster.text.start < idx && idx <= cluster.text.end);
+        match (leading, trailing) {
+            (Some(leading), Some(trailing))
+                if leading.line == trailing.line && leading.rtl != trailing.rtl =>
+            {
+                let primary = position(leading);
+                let secondary = position(trailing);
+                if primary.point == secondary.point {
+                    (primary, None)
+                } else {
+                    (primary, Some(secondary))
+                }
+            }
+            (Some(cluster), _) => (position(cluster), None),
+            (None, Some(cluster)) => (position(cluster), None),
+            (None, None) => match clusters.iter().max_by_key(|cluster| cluster.text.end) {
+                Some(cluster) => (position(cluster), None),
+                None => (HitTestPosition::default(), None),
+            },
         }
     }
 
-    pub(crate) fn draw_text(&self, ctx: &mut WgpuRenderContext, translate: [f32; 2]) {
+    /// Add the glyphs to the frame, in `color` if given instead of their text
+    /// colors.
+    pub(crate) fn draw_text(
+        &self,
+        ctx: &mut WgpuRenderContext,
+        translate: [f32; 2],
+        color: Option<[f32; 4]>,
+    ) {
         let geometry = self.geometry.borrow();
         if geometry.vertices.len() == 0 {
             return;
@@ -288,6 +737,9 @@ impl WgpuTextLayout {
                 let mut v = v.clone();
                 v.translate = translate;
                 v.primitive_id = primivite_id;
+                if let Some(color) = color {
+                    v.color = color;
+                }
                 v
             })
             .collect();
@@ -298,7 +750,7 @@ impl WgpuTextLayout {
 
     pub fn cursor_line_for_text_position(&self, text_pos: usize) -> Line {
         let pos = self.hit_test_text_position(text_pos);
-        let line_metric = self.line_metric(0).unwrap();
+        let line_metric = self.line_metric(pos.line).unwrap_or_default();
         let p0 = (pos.point.x, line_metric.y_offset);
         let p1 = (pos.point.x, line_metric.y_offset + line_metric.height);
         Line::new(p0, p1)
@@ -307,6 +759,7 @@ impl WgpuTextLayout {
 
 pub struct WgpuTextLayoutBuilder {
     width: f64,
+    alignment: TextAlignment,
     state: WgpuText,
     text: String,
     attrs: Attributes,
@@ -316,6 +769,7 @@ impl WgpuTextLayoutBuilder {
     pub(crate) fn new(text: impl TextStorage, state: WgpuText) -> Self {
         Self {
             width: f64::MAX,
+            alignment: TextAlignment::Start,
             text: text.as_str().to_string(),
             attrs: Default::default(),
             state,
@@ -336,6 +790,7 @@ impl WgpuTextLayoutBuilder {
         let mut text_layout = WgpuTextLayout::new(self.text, state);
         text_layout.set_attrs(self.attrs);
         text_layout.set_width(self.width);
+        text_layout.set_alignment(self.alignment);
         text_layout.rebuild(is_mono, tab_width, bounds);
         text_layout
     }
@@ -345,6 +800,7 @@ impl WgpuTextLayoutBuilder {
         let mut text_layout = WgpuTextLayout::new(self.text, state);
         text_layout.set_attrs(self.attrs);
         text_layout.set_width(self.width);
+        text_layout.set_alignment(self.alignment);
         text_layout.rebuild(false, 8, Some(bounds));
         text_layout
     }
@@ -376,7 +832,8 @@ impl TextLayoutBuilder for WgpuTextLayoutBuilder {
         self
     }
 
-    fn alignment(self, alignment: piet::TextAlignment) -> Self {
+    fn alignment(mut self, alignment: TextAlignment) -> Self {
+        self.alignment = alignment;
         self
     }
 
@@ -402,6 +859,7 @@ impl TextLayoutBuilder for WgpuTextLayoutBuilder {
         let mut text_layout = WgpuTextLayout::new(self.text, state);
         text_layout.set_attrs(self.attrs);
         text_layout.set_width(self.width);
+        text_layout.set_alignment(self.alignment);
         text_layout.rebuild(false, 8, None);
         Ok(text_layout)
     }
@@ -409,21 +867,29 @@ impl TextLayoutBuilder for WgpuTextLayoutBuilder {
 
 impl TextLayout for WgpuTextLayout {
     fn size(&self) -> Size {
-        if self.glyphs.borrow().len() == 0 {
-            let ref_glyph = self.ref_glyph.borrow();
-            Size::new(0.0, ref_glyph.rect.height())
-        } else {
-            let glyphs = self.glyphs.borrow();
-
-            let last_glyph = &glyphs[glyphs.len() - 1];
-            let width = last_glyph.rect.x0 + last_glyph.width;
-            let height = last_glyph.rect.y1;
-            Size::new(width as f64, height as f64)
-        }
+        let (width, height) =
+            self.clusters
+                .borrow()
+                .iter()
+                .fold((0.0, 0.0), |(width, height), cluster| {
+                    let right = match cluster.hanging {
+                        true => 0.0,
+                        false => cluster.x + cluster.width,
+                    };
+                    (
+                        f64::max(width, right),
+                        f64::max(height, cluster.y + cluster.height),
+                    )
+                });
+        Size::new(width, height)
     }
 
     fn trailing_whitespace_width(&self) -> f64 {
-        0.0
+        self.clusters
+            .borrow()
+            .iter()
+            .map(|cluster| cluster.x + cluster.width)
+            .fold(0.0, f64::max)
     }
 
     fn image_bounds(&self) -> piet::kurbo::Rect {
@@ -435,68 +901,127 @@ impl TextLayout for WgpuTextLayout {
     }
 
     fn line_text(&self, line_number: usize) -> Option<&str> {
-        Some(&self.text)
+        let lines = self.lines.borrow();
+        let line = lines.get(line_number)?;
+        Some(&self.text[line.start_offset..line.end_offset])
     }
 
     fn line_metric(&self, line_number: usize) -> Option<LineMetric> {
-        let mut metric = LineMetric {
-            start_offset: 0,
-            end_offset: self.text.len(),
-            trailing_whitespace: 0,
-            baseline: 0.0,
-            height: 0.0,
-            y_offset: 0.0,
-        };
-        let glyph = &self.ref_glyph.borrow();
-        metric.baseline = glyph.metric.ascent;
-        metric.height = glyph.metric.ascent - glyph.metric.descent + glyph.metric.line_gap;
-        Some(metric)
+        self.lines.borrow().get(line_number).cloned()
     }
 
     fn line_count(&self) -> usize {
-        0
+        self.lines.borrow().len()
     }
 
     fn hit_test_point(&self, point: Point) -> HitTestPoint {
         let mut hit = HitTestPoint::default();
-        if self.glyphs.borrow().len() == 0 {
-            return hit;
-        }
+        let clusters = self.clusters.borrow();
+        let (first, last) = match (clusters.first(), clusters.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return hit,
+        };
 
-        let glyphs = self.glyphs.borrow();
-        let mut index = None;
-        for (i, glyph) in glyphs.iter().enumerate() {
-            if point.x < glyph.rect.x0 + glyph.rect.width() / 2.0 {
-                index = Some(i);
-                break;
-            }
-            if point.x < glyph.rect.x1 {
-                index = Some(i + 1);
-                break;
+        // The line at the point, or the nearest one.
+        let line = clusters
+            .iter()
+            .rev()
+            .find(|cluster| cluster.y <= point.y)
+            .unwrap_or(first)
+            .line;
+
+        let mut nearest: Option<(&LayoutCluster, f64)> = None;
+        for cluster in clusters.iter().filter(|cluster| cluster.line == line) {
+            let distance = if point.x < cluster.x {
+                cluster.x - point.x
+            } else if point.x > cluster.x + cluster.width {
+                point.x - cluster.x - cluster.width
+            } else {
+                0.0
+            };
+            if !matches!(nearest, Some((_, d)) if d <= distance) {
+                nearest = Some((cluster, distance));
             }
         }
-        hit.idx = index.unwrap_or(glyphs.len());
-        hit.is_inside = index.is_some();
+        if let Some((cluster, distance)) = nearest {
+            hit.idx = cluster.hit_test(&self.text, point.x);
+            hit.is_inside = distance == 0.0 && point.y >= first.y && point.y < last.y + last.height;
+        }
         hit
     }
 
     fn hit_test_text_position(&self, idx: usize) -> HitTestPosition {
-        if self.glyphs.borrow().len() == 0 {
-            return HitTestPosition::default();
+        self.hit_test_text_position_split(idx).0
+    }
+}
+
+/// The x of the start of a line `width` wide, aligned within `align_width`.
+fn line_offset(alignment: TextAlignment, rtl: bool, align_width: f64, width: f64) -> f64 {
+    let slack = align_width - width;
+    match (alignment, rtl) {
+        (TextAlignment::Center, _) => slack / 2.0,
+        (TextAlignment::Start | TextAlignment::Justified, false) | (TextAlignment::End, true) => {
+            0.0
+        }
+        (TextAlignment::Start | TextAlignment::Justified, true) | (TextAlignment::End, false) => {
+            slack
         }
+    }
+}
 
-        let glyphs = self.glyphs.borrow();
+/// Whether `c` ends a line, which it doesn't take up any room on.
+fn is_line_separator(c: char) -> bool {
+    matches!(
+        c,
+        '\n' | '\r' | '\u{b}' | '\u{c}' | '\u{85}' | '\u{2028}' | '\u{2029}'
+    )
+}
 
-        let cur_glyph = &glyphs[idx.min(glyphs.len() - 1)];
-        let mut x = cur_glyph.rect.x0;
-        if idx >= glyphs.len() {
-            x = cur_glyph.rect.x1;
-        }
+/// The level of the paragraph the byte at `index` is in.
+fn paragraph_level(bidi: &BidiInfo, index: usize) -> Level {
+    bidi.paragraphs
+        .iter()
+        .find(|paragraph| paragraph.range.contains(&index))
+        .map_or_else(Level::ltr, |paragraph| paragraph.level)
+}
 
-        let mut pos = HitTestPosition::default();
-        pos.point = Point::new(x as f64, 0.0);
-        pos
+/// The levels of the bytes of a line, with whitespace at its end and before
+/// tabs and paragraph separators reset to the level of the paragraph, as
+/// rule L1 of the bidi algorithm has it.
+fn line_levels(bidi: &BidiInfo, line: Range<usize>) -> Vec<Level> {
+    let paragraph_level = |index| paragraph_level(bidi, index);
+
+    let mut levels = bidi.levels[line.clone()].to_vec();
+    let mut whitespace_start = None;
+    for (i, c) in bidi.text[line.clone()].char_indices() {
+        match bidi.original_classes[line.start + i] {
+            BidiClass::B | BidiClass::S => {
+                let level = paragraph_level(line.start + i);
+                for l in &mut levels[whitespace_start.unwrap_or(i)..i + c.len_utf8()] {
+                    *l = level;
+                }
+                whitespace_start = None;
+            }
+            BidiClass::WS | BidiClass::FSI | BidiClass::LRI | BidiClass::RLI | BidiClass::PDI => {
+                whitespace_start.get_or_insert(i);
+            }
+            // Removed by rule X9, so they don't end the whitespace.
+            BidiClass::BN
+            | BidiClass::LRE
+            | BidiClass::RLE
+            | BidiClass::LRO
+            | BidiClass::RLO
+            | BidiClass::PDF => {}
+            _ => whitespace_start = None,
+        }
     }
+    if let Some(start) = whitespace_start {
+        let level = paragraph_level(line.end - 1);
+        for l in &mut levels[start..] {
+            *l = level;
+        }
+    }
+    levels
 }
 
 #[derive(Default)]
@@ -582,3 +1107,175 @@ impl Attributes {
         self.defaults.weight
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WgpuRenderer;
+
+    fn layout(
+        text: &str,
+        builder: impl FnOnce(WgpuTextLayoutBuilder) -> WgpuTextLayoutBuilder,
+    ) -> WgpuTextLayout {
+        let mut text_factory = WgpuRenderer::new_cpu().text();
+        builder(text_factory.new_text_layout(text.to_string()))
+            .build()
+            .unwrap()
+    }
+
+    fn clusters(layout: &WgpuTextLayout) -> Vec<Range<usize>> {
+        let clusters = layout.clusters.borrow();
+        clusters
+            .iter()
+            .map(|cluster| cluster.text.clone())
+            .collect()
+    }
+
+    #[test]
+    fn ligature_is_one_cluster() {
+        // None of the bundled fonts the layout can pick has an "fi" ligature.
+        if SystemSource::new()
+            .select_family_by_name("DejaVu Sans")
+            .is_err()
+        {
+            eprintln!("skipped: DejaVu Sans isn't installed");
+            return;
+        }
+        let dejavu = FontFamily::new_unchecked("DejaVu Sans");
+        let layout = layout("fin", |builder| builder.default_attribute(dejavu));
+        assert_eq!(clusters(&layout), [0..2, 2..3]);
+        let caret = layout.hit_test_text_position(1).point.x;
+        let end = layout.hit_test_text_position(2).point.x;
+        assert!(0.0 < caret && caret < end);
+    }
+
+    #[test]
+    fn direction_boundary_splits_the_caret() {
+        // "ab" then alef and bet, which are laid out right to left.
+        let layout = layout("ab\u{5d0}\u{5d1}", |builder| builder);
+        let (primary, secondary) = layout.hit_test_text_position_split(2);
+        let secondary = secondary.expect("no second caret at the direction boundary");
+        // The leading edge of the alef is the right end of the line, the
+        // trailing edge of the b is where the right-to-left run starts.
+        assert_eq!(primary.point.x, layout.size().width);
+        let b = layout.clusters.borrow()[1].clone();
+        assert_eq!(b.text, 1..2);
+        assert_eq!(secondary.point.x, b.x + b.width);
+        assert!(secondary.point.x < primary.point.x);
+        assert_eq!(primary.line, secondary.line);
+        // Within a run there's one caret.
+        assert!(layout.hit_test_text_position_split(1).1.is_none());
+        assert!(layout.hit_test_text_position_split(4).1.is_none());
+    }
+
+    fn lines(layout: &WgpuTextLayout) -> Vec<&str> {
+        (0..layout.line_count())
+            .map(|line| layout.line_text(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn wraps_at_word_boundaries() {
+        let width = layout("hello wor", |builder| builder).size().width;
+        let layout = layout("hello world", |builder| builder.max_width(width));
+        assert_eq!(lines(&layout), ["hello ", "world"]);
+        assert!(layout.size().width <= width);
+    }
+
+    #[test]
+    fn breaks_lines_at_newlines() {
+        let layout = layout("one two\nthree", |builder| builder);
+        assert_eq!(lines(&layout), ["one two\n", "three"]);
+    }
+
+    #[test]
+    fn splits_words_longer_than_a_line() {
+        let width = layout("abcd", |builder| builder).size().width;
+        let layout = layout("abcdefghij", |builder| builder.max_width(width));
+        let lines = lines(&layout);
+        assert!(lines.len() >= 3, "{:?}", lines);
+        assert_eq!(lines[0], "abcd");
+        assert_eq!(lines.concat(), "abcdefghij");
+        assert!(layout.size().width <= width);
+    }
+
+    /// The left and right edges of the ink of a line, without the whitespace
+    /// hanging off its end.
+    fn line_extent(layout: &WgpuTextLayout, line: usize) -> (f64, f64) {
+        let clusters = layout.clusters.borrow();
+        clusters
+            .iter()
+            .filter(|cluster| cluster.line == line && !cluster.hanging)
+            .fold((f64::MAX, f64::MIN), |(left, right), cluster| {
+                (left.min(cluster.x), right.max(cluster.x + cluster.width))
+            })
+    }
+
+    #[test]
+    fn aligns_lines() {
+        let width = layout("ab cd e", |builder| builder).size().width;
+        let last_width = layout("ef", |builder| builder).size().width;
+        let aligned = |alignment| {
+            layout("ab cd ef", |builder| {
+                builder.max_width(width).alignment(alignment)
+            })
+        };
+
+        let start = aligned(TextAlignment::Start);
+        assert_eq!(lines(&start), ["ab cd ", "ef"]);
+        let first_width = line_extent(&start, 0).1;
+        assert_eq!(line_extent(&start, 1), (0.0, last_width));
+
+        let center = aligned(TextAlignment::Center);
+        let (left, right) = line_extent(&center, 1);
+        assert!((left - (width - last_width) / 2.0).abs() < 1e-9);
+        assert!((right - (width + last_width) / 2.0).abs() < 1e-9);
+        assert!((line_extent(&center, 0).0 - (width - first_width) / 2.0).abs() < 1e-9);
+
+        let end = aligned(TextAlignment::End);
+        assert!((line_extent(&end, 0).1 - width).abs() < 1e-9);
+        assert!((line_extent(&end, 1).1 - width).abs() < 1e-9);
+        assert!((line_extent(&end, 1).0 - (width - last_width)).abs() < 1e-9);
+
+        // Justified lines are stretched to the width by their spaces, but the
+        // last line of a paragraph is not.
+        let justified = aligned(TextAlignment::Justified);
+        assert_eq!(line_extent(&justified, 0).0, 0.0);
+        assert!((line_extent(&justified, 0).1 - width).abs() < 1e-9);
+        assert!(first_width < width);
+        assert_eq!(line_extent(&justified, 1), (0.0, last_width));
+        let hit = justified.hit_test_text_position(3).point.x;
+        assert!(hit > start.hit_test_text_position(3).point.x);
+    }
+
+    #[test]
+    fn line_metrics_include_the_trailing_empty_line() {
+        let layout = layout("a\nb\n", |builder| builder);
+        assert_eq!(layout.line_count(), 3);
+        assert_eq!(lines(&layout), ["a\n", "b\n", ""]);
+        assert_eq!(layout.line_text(3), None);
+        assert!(layout.line_metric(3).is_none());
+
+        let metrics: Vec<LineMetric> = (0..3)
+            .map(|line| layout.line_metric(line).unwrap())
+            .collect();
+        let offsets: Vec<_> = metrics
+            .iter()
+            .map(|metric| (metric.start_offset, metric.end_offset))
+            .collect();
+        assert_eq!(offsets, [(0, 2), (2, 4), (4, 4)]);
+        assert_eq!(metrics[0].trailing_whitespace, 1);
+        assert_eq!(metrics[2].trailing_whitespace, 0);
+        let height = metrics[0].height;
+        for (line, metric) in metrics.iter().enumerate() {
+            assert!(metric.height > 0.0 && metric.baseline > 0.0);
+            assert_eq!(metric.height, height);
+            assert!((metric.y_offset - line as f64 * height).abs() < 1e-9);
+        }
+
+        // The caret after the last newline is on the empty line.
+        let caret = layout.hit_test_text_position(4);
+        assert_eq!(caret.line, 2);
+        assert_eq!(caret.point.y, metrics[2].y_offset + metrics[2].baseline);
+    }
+}
@@ -0,0 +1,56 @@
+//! Tests for `capture_image_area`, on the GPU if there's an adapter and on
+//! the CPU otherwise.
+
+use piet::kurbo::{Rect, Size};
+use piet::{Color, RenderContext};
+use piet_wgpu::{Piet, WgpuRenderer};
+
+const SIZE: f64 = 8.0;
+
+fn renderer() -> WgpuRenderer {
+    let mut renderer = WgpuRenderer::new_headless().unwrap();
+    renderer.set_size(Size::new(SIZE, SIZE));
+    renderer
+}
+
+/// The pixel at `x`, `y` of the last frame, premultiplied.
+fn pixel(renderer: &mut WgpuRenderer, x: usize, y: usize) -> Vec<u8> {
+    let pixels = renderer.read_pixels().unwrap();
+    let index = (y * SIZE as usize + x) * 4;
+    pixels[index..index + 4].to_vec()
+}
+
+fn center(renderer: &mut WgpuRenderer) -> Vec<u8> {
+    pixel(renderer, SIZE as usize / 2, SIZE as usize / 2)
+}
+
+#[test]
+fn frames_with_captures_start_transparent() {
+    let mut renderer = renderer();
+    let area = Rect::new(0.0, 0.0, SIZE, SIZE);
+
+    let mut rc = Piet::new(&mut renderer);
+    rc.fill(area, &Color::rgb8(255, 0, 0));
+    rc.capture_image_area(area).unwrap();
+    rc.finish().unwrap();
+    drop(rc);
+    assert_eq!(center(&mut renderer), [255, 0, 0, 255]);
+
+    // Nothing is drawn in the second frame before its capture, and only the
+    // top left pixel after it.
+    let mut rc = Piet::new(&mut renderer);
+    let captured = rc.capture_image_area(area).unwrap();
+    let blue = Rect::new(0.0, 0.0, 1.0, 1.0);
+    rc.fill(blue, &Color::rgb8(0, 0, 255));
+    rc.finish().unwrap();
+    drop(rc);
+    assert_eq!(center(&mut renderer), [0, 0, 0, 0]);
+    assert_eq!(pixel(&mut renderer, 0, 0), [0, 0, 255, 255]);
+
+    // The capture of the second frame is transparent too.
+    let mut rc = Piet::new(&mut renderer);
+    rc.draw_image(&captured, area, piet::InterpolationMode::NearestNeighbor);
+    rc.finish().unwrap();
+    drop(rc);
+    assert_eq!(center(&mut renderer), [0, 0, 0, 0]);
+}
@@ -0,0 +1,76 @@
+//! Decoding images with the `decode` feature.
+//!
+//! The fixtures in `tests/fixtures` are:
+//!
+//! - `rotated.jpg`, 16x8 pixels, red on the left and blue on the right, with
+//!   an EXIF orientation that turns it 90° clockwise.
+//! - `display-p3.png`, 2x2 pixels of (128, 64, 64) with a Display P3 ICC
+//!   profile.
+//! - `animated.gif`, 4x3 pixels, a red frame and then a blue one.
+
+#![cfg(feature = "decode")]
+
+use piet_wgpu::{Image, InterpolationMode, Piet, RenderContext, WgpuRenderer};
+
+/// Decode `name` from the fixtures, and return its size and its pixels as
+/// drawn on the CPU, premultiplied RGBA row by row.
+fn decode(name: &str) -> ((usize, usize), Vec<u8>) {
+    let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+    let mut renderer = WgpuRenderer::new_cpu();
+    let image = renderer
+        .decode_image(&std::fs::read(path).unwrap())
+        .unwrap();
+    let size = image.size();
+    renderer.set_size(size);
+    let mut piet = Piet::new(&mut renderer);
+    piet.draw_image(&image, size.to_rect(), InterpolationMode::NearestNeighbor);
+    piet.finish().unwrap();
+    drop(piet);
+    (
+        (size.width as usize, size.height as usize),
+        renderer.read_pixels().unwrap(),
+    )
+}
+
+fn pixel(((width, _), pixels): &((usize, usize), Vec<u8>), x: usize, y: usize) -> [u8; 4] {
+    let i = (y * width + x) * 4;
+    [pixels[i], pixels[i + 1], pixels[i + 2], pixels[i + 3]]
+}
+
+fn assert_close(actual: [u8; 4], expected: [u8; 4], tolerance: u8) {
+    assert!(
+        actual
+            .iter()
+            .zip(expected)
+            .all(|(&a, e)| a.abs_diff(e) <= tolerance),
+        "{:?} isn't within {} of {:?}",
+        actual,
+        tolerance,
+        expected
+    );
+}
+
+#[test]
+fn exif_orientation_is_applied() {
+    let image = decode("rotated.jpg");
+    assert_eq!(image.0, (8, 16));
+    assert_close(pixel(&image, 4, 3), [255, 0, 0, 255], 8);
+    assert_close(pixel(&image, 4, 12), [0, 0, 255, 255], 8);
+}
+
+#[test]
+fn icc_profile_is_converted_to_srgb() {
+    let image = decode("display-p3.png");
+    assert_eq!(image.0, (2, 2));
+    // (128, 64, 64) in Display P3 is about (138, 59, 62) in sRGB.
+    assert_close(pixel(&image, 1, 1), [138, 59, 62, 255], 2);
+}
+
+#[test]
+fn gif_decodes_its_first_frame() {
+    let image = decode("animated.gif");
+    assert_eq!(image.0, (4, 3));
+    assert_close(pixel(&image, 2, 1), [255, 0, 0, 255], 0);
+}
@@ -0,0 +1,300 @@
+//! Golden-image tests for the `piet::samples` pictures.
+//!
+//! Every sample is rendered offscreen and written as a PNG to
+//! `$CARGO_TARGET_TMPDIR/samples`, then compared with the reference of the
+//! same name in `tests/golden` with a perceptual tolerance. The test fails if
+//! they differ, if a sample has no reference, or if a sample in
+//! [`UNSUPPORTED`] starts rendering. Without an adapter the samples are
+//! rasterized on the CPU.
+//!
+//! Run with `PIET_WGPU_BLESS=1` to replace the references with the current
+//! output of every sample that renders successfully. The references are
+//! rendered on the CPU, and the ones in [`SANS_SERIF_TEXT`] on a machine
+//! where the sans-serif font is DejaVu Sans.
+//!
+//! On machines with an adapter, the samples are also drawn with the CPU
+//! rasterizer and compared with the GPU pipeline's output.
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+
+use font_kit::family_name::FamilyName;
+use font_kit::properties::Properties;
+use font_kit::source::SystemSource;
+use piet::kurbo::Size;
+use piet::samples;
+use piet_wgpu::{ImageFormat, Piet, RenderContext, WgpuRenderer};
+
+/// The samples that use parts of the backend that aren't implemented yet,
+/// loading fonts by name or from data, and are expected to fail.
+const UNSUPPORTED: &[usize] = &[0, 5, 9, 12, 13, 14];
+
+/// The samples that draw text in the system's sans-serif font. Their
+/// references were drawn in DejaVu Sans, so they're only compared with them
+/// where sans-serif is DejaVu Sans.
+const SANS_SERIF_TEXT: &[usize] = &[7, 8, 10, 11];
+
+/// The pixel scale samples are rendered at.
+const SCALE: f64 = 2.0;
+
+/// The largest YIQ color distance between two pixels that are still
+/// considered equal, as a fraction of the largest possible distance.
+const PIXEL_THRESHOLD: f64 = 0.1;
+
+/// The fraction of pixels that may differ before a sample fails, to leave
+/// room for differences in anti-aliasing between adapters.
+const MAX_DIFFERENT_PIXELS: f64 = 0.005;
+
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+enum Outcome {
+    Match,
+    Blessed,
+    Unsupported(String),
+    Skipped(String),
+    Different(String),
+}
+
+#[test]
+fn samples_match_golden_images() {
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("samples");
+    let golden_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    std::fs::create_dir_all(&out_dir).unwrap();
+    let bless = std::env::var_os("PIET_WGPU_BLESS").is_some();
+    let sans_serif = sans_serif_family();
+    let reference_fonts = sans_serif.as_deref() == Some("DejaVu Sans");
+
+    let mut regressions = Vec::new();
+    for number in 0..samples::SAMPLE_COUNT {
+        let name = format!("wgpu-test-{}.png", number);
+        let golden_path = golden_dir.join(&name);
+        let unsupported = UNSUPPORTED.contains(&number);
+        let system_fonts = SANS_SERIF_TEXT.contains(&number) && !reference_fonts;
+        let outcome = match render_sample(WgpuRenderer::new_headless, number) {
+            Ok(image) => {
+                save_png(&out_dir.join(&name), &image);
+                if system_fonts {
+                    Outcome::Skipped(format!(
+                        "the sans-serif font is {}, not DejaVu Sans",
+                        sans_serif.as_deref().unwrap_or("missing")
+                    ))
+                } else if bless {
+                    save_png(&golden_path, &image);
+                    Outcome::Blessed
+                } else if unsupported {
+                    Outcome::Different(
+                        "renders now, remove it from UNSUPPORTED and bless it".to_string(),
+                    )
+                } else if golden_path.exists() {
+                    compare(&load_png(&golden_path), &image, &out_dir, &name)
+                } else {
+                    Outcome::Different(format!(
+                        "no reference, run with PIET_WGPU_BLESS=1 to write {}",
+                        golden_path.display()
+                    ))
+                }
+            }
+            Err(e) if unsupported => Outcome::Unsupported(e),
+            Err(e) => Outcome::Different(e),
+        };
+
+        let status = match &outcome {
+            Outcome::Match => "ok".to_string(),
+            Outcome::Blessed => "blessed".to_string(),
+            Outcome::Unsupported(e) => format!("not supported yet: {}", e),
+            Outcome::Skipped(e) => format!("skipped: {}", e),
+            Outcome::Different(e) => format!("FAILED: {}", e),
+        };
+        println!("sample {:02}: {}", number, status);
+
+        if let Outcome::Different(e) = outcome {
+            regressions.push(format!("sample {}: {}", number, e));
+        }
+    }
+
+    assert!(
+        regressions.is_empty(),
+        "samples don't match tests/golden:\n{}",
+        regressions.join("\n")
+    );
+}
+
+#[test]
+fn cpu_matches_gpu() {
+    if WgpuRenderer::new_headless().map_or(true, |renderer| renderer.is_cpu()) {
+        println!("skipped: no adapter to compare the CPU rasterizer with");
+        return;
+    }
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("cpu-samples");
+    std::fs::create_dir_all(&out_dir).unwrap();
+
+    let mut mismatches = Vec::new();
+    for number in (0..samples::SAMPLE_COUNT).filter(|n| !UNSUPPORTED.contains(n)) {
+        let name = format!("wgpu-test-{}.png", number);
+        let gpu = render_sample(WgpuRenderer::new_headless, number).unwrap();
+        let cpu = render_sample(|| Ok(WgpuRenderer::new_cpu()), number).unwrap();
+        save_png(&out_dir.join(&name), &cpu);
+        let status = match compare(&gpu, &cpu, &out_dir, &name) {
+            Outcome::Different(e) => {
+                mismatches.push(format!("sample {}: {}", number, e));
+                format!("FAILED: {}", e)
+            }
+            _ => "ok".to_string(),
+        };
+        println!("sample {:02}: {}", number, status);
+    }
+
+    assert!(
+        mismatches.is_empty(),
+        "the CPU rasterizer doesn't match the GPU pipeline:\n{}",
+        mismatches.join("\n")
+    );
+}
+
+/// The family of the system's sans-serif font, which the text samples are
+/// drawn in.
+fn sans_serif_family() -> Option<String> {
+    let handle = SystemSource::new()
+        .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+        .ok()?;
+    Some(handle.load().ok()?.family_name())
+}
+
+/// Render a sample with a renderer from `new_renderer`, turning errors and
+/// panics from unimplemented parts of the backend into an error message.
+fn render_sample(
+    new_renderer: impl FnOnce() -> Result<WgpuRenderer, piet::Error>,
+    number: usize,
+) -> Result<Image, String> {
+    let size = samples::get::<Piet>(number)
+        .map_err(|e| e.to_string())?
+        .size()
+        * SCALE;
+    let mut renderer = new_renderer().map_err(|e| e.to_string())?;
+    renderer.set_size(Size::new(size.width.trunc(), size.height.trunc()));
+    renderer.set_scale(SCALE);
+
+    let drawn = panic::catch_unwind(AssertUnwindSafe(|| {
+        let mut rc = Piet::new(&mut renderer);
+        let sample = samples::get(number).map_err(|_| piet::Error::InvalidSampleArgs)?;
+        sample.draw(&mut rc)?;
+        rc.finish()
+    }));
+    match drawn {
+        Ok(result) => result.map_err(|e| e.to_string())?,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "panicked".to_string());
+            return Err(message);
+        }
+    }
+
+    let image = renderer
+        .to_image_buf(ImageFormat::RgbaSeparate)
+        .map_err(|e| e.to_string())?;
+    Ok(Image {
+        width: image.width() as u32,
+        height: image.height() as u32,
+        pixels: image.raw_pixels().to_vec(),
+    })
+}
+
+/// Compare two images pixel by pixel in YIQ space, which weighs differences
+/// roughly the way they are perceived.
+fn compare(golden: &Image, image: &Image, out_dir: &Path, name: &str) -> Outcome {
+    if (golden.width, golden.height) != (image.width, image.height) {
+        return Outcome::Different(format!(
+            "size is {}x{}, expected {}x{}",
+            image.width, image.height, golden.width, golden.height
+        ));
+    }
+
+    // 35215 is the YIQ distance between black and white.
+    let max_delta = 35215.0 * PIXEL_THRESHOLD * PIXEL_THRESHOLD;
+    let mut diff = Vec::with_capacity(image.pixels.len());
+    let mut different = 0;
+    for (a, b) in golden.pixels.chunks(4).zip(image.pixels.chunks(4)) {
+        let delta = yiq_delta(a, b);
+        if delta > max_delta {
+            different += 1;
+            diff.extend_from_slice(&[255, 0, 0, 255]);
+        } else {
+            let y = 255 - (255 - rgb_to_yiq(a).0 as u8) / 4;
+            diff.extend_from_slice(&[y, y, y, 255]);
+        }
+    }
+
+    let fraction = different as f64 / (image.width * image.height).max(1) as f64;
+    if fraction <= MAX_DIFFERENT_PIXELS {
+        return Outcome::Match;
+    }
+
+    let diff_path = out_dir.join(name.replace(".png", "-diff.png"));
+    save_png(
+        &diff_path,
+        &Image {
+            width: image.width,
+            height: image.height,
+            pixels: diff,
+        },
+    );
+    Outcome::Different(format!(
+        "{:.2}% of pixels differ, see {}",
+        fraction * 100.0,
+        diff_path.display()
+    ))
+}
+
+fn yiq_delta(a: &[u8], b: &[u8]) -> f64 {
+    let (y1, i1, q1) = rgb_to_yiq(a);
+    let (y2, i2, q2) = rgb_to_yiq(b);
+    let (y, i, q) = (y1 - y2, i1 - i2, q1 - q2);
+    0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q
+}
+
+/// Convert a pixel to YIQ after blending it onto white.
+fn rgb_to_yiq(pixel: &[u8]) -> (f64, f64, f64) {
+    let a = pixel[3] as f64 / 255.0;
+    let blend = |c: u8| 255.0 + (c as f64 - 255.0) * a;
+    let (r, g, b) = (blend(pixel[0]), blend(pixel[1]), blend(pixel[2]));
+    (
+        r * 0.29889531 + g * 0.58662247 + b * 0.11448223,
+        r * 0.59597799 - g * 0.27417610 - b * 0.32180189,
+        r * 0.21147017 - g * 0.52261711 + b * 0.31114694,
+    )
+}
+
+fn save_png(path: &Path, image: &Image) {
+    let file = BufWriter::new(File::create(path).unwrap());
+    let mut encoder = png::Encoder::new(file, image.width, image.height);
+    encoder.set_color(png::ColorType::RGBA);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(&image.pixels).unwrap();
+}
+
+fn load_png(path: &Path) -> Image {
+    let decoder = png::Decoder::new(File::open(path).unwrap());
+    let (info, mut reader) = decoder.read_info().unwrap();
+    assert_eq!(
+        info.color_type,
+        png::ColorType::RGBA,
+        "references are stored as RGBA"
+    );
+    let mut pixels = vec![0; info.buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+    Image {
+        width: info.width,
+        height: info.height,
+        pixels,
+    }
+}